@@ -0,0 +1,569 @@
+use std::f64::consts::PI;
+
+use crate::{
+    color::Color,
+    hittable::{HitRecord, Hittable},
+    integrator::{sample_delta_lights, Integrate, Integrator, Scene},
+    interval::Interval,
+    material::{MatFn, SrecData},
+    medium::PathMedium,
+    onb::Onb,
+    pdf::PDF,
+    ray::Ray,
+    utils::{random_double, INF},
+    vec3::{dot, random_cosine_direction, unit_vector, Point3, Vec3},
+};
+
+// Bidirectional path tracing after Veach's thesis and pbrt. Subpaths are traced from the camera and
+// from a point sampled on scene.lights, and every pair of vertices is connected and weighted with
+// the balance heuristic. Connections straight to the camera (light tracing) are not used.
+//
+// scene.lights must hold the actual emitters of the scene, since light subpaths start on them.
+// Delta lights are sampled from every camera vertex, the only strategy that can reach them.
+// Light subpaths never start from the sky either, so the sun is sampled from every camera vertex
+// and weighted against camera paths escaping towards it, apart from the other strategies.
+// Both subpaths follow the media they pass through with a PathMedium, with lights assumed to
+// sit in air.
+#[derive(Clone)]
+pub struct Bdpt;
+
+impl Bdpt {
+    pub fn new() -> Integrator {
+        Integrator::Bdpt(Bdpt)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    p: Point3,
+    time: f64,
+    rec: Option<HitRecord<'a>>,
+    r_in: Option<Ray>, // the ray that arrived at this vertex
+    beta: Color,
+    delta: bool,
+    pdf_fwd: f64, // pdf per unit area of sampling this vertex from the previous one
+    pdf_rev: f64, // and from the next one, in the other direction
+    medium: PathMedium<'a>, // what the path arrived at this vertex through
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(r: &Ray) -> Vertex<'a> {
+        Vertex {
+            kind: VertexKind::Camera,
+            p: r.origin(),
+            time: r.time(),
+            rec: None,
+            r_in: None,
+            beta: Color::new(1., 1., 1.),
+            delta: false,
+            pdf_fwd: 1.,
+            pdf_rev: 0.,
+            medium: PathMedium::default(),
+        }
+    }
+
+    fn light(rec: HitRecord<'a>, time: f64, beta: Color, pdf_fwd: f64) -> Vertex<'a> {
+        Vertex {
+            kind: VertexKind::Light,
+            p: rec.p,
+            time,
+            rec: Some(rec),
+            r_in: None,
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.,
+            medium: PathMedium::default(),
+        }
+    }
+
+    fn surface(rec: HitRecord<'a>, r_in: &Ray, beta: Color, medium: PathMedium<'a>) -> Vertex<'a> {
+        Vertex {
+            kind: VertexKind::Surface,
+            p: rec.p,
+            time: r_in.time(),
+            rec: Some(rec),
+            r_in: Some(r_in.clone()),
+            beta,
+            delta: false,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            medium,
+        }
+    }
+
+    fn outward_normal(&self) -> Vec3 {
+        match &self.rec {
+            Some(rec) if rec.front_face => rec.normal,
+            Some(rec) => -rec.normal,
+            None => Vec3::new_zero(),
+        }
+    }
+
+    // Volumes have no meaningful normal, so no cosine terms apply to them
+    fn on_surface(&self) -> bool {
+        match &self.rec {
+            Some(rec) => !rec.mat.is_volumetric(),
+            None => false,
+        }
+    }
+
+    // The hit record with its normal facing the point from
+    fn facing(&self, from: &Point3) -> Option<(HitRecord<'a>, Ray)> {
+        let rec = self.rec.as_ref()?;
+        let r = Ray::new_timed(*from, self.p - *from, self.time);
+        let outward_normal = self.outward_normal();
+        Some((rec.set_face_normal(&r, &outward_normal), r))
+    }
+
+    // Turns a pdf per unit solid angle at this vertex into one per unit area at next
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let dist_squared = w.length_squared();
+        if dist_squared == 0. {
+            return 0.;
+        }
+
+        let pdf = pdf / dist_squared;
+        if next.on_surface() {
+            pdf * dot(&next.outward_normal(), &w).abs() / dist_squared.sqrt()
+        } else {
+            pdf
+        }
+    }
+
+    // BRDF times cosine for light continuing from this vertex to next
+    fn f(&self, next: &Vertex) -> Color {
+        self.f_dir(&(next.p - self.p))
+    }
+
+    fn f_dir(&self, direction: &Vec3) -> Color {
+        match (&self.rec, &self.r_in) {
+            (Some(rec), Some(r_in)) => {
+                let scattered = Ray::new_timed(self.p, *direction, self.time);
+                self.medium.eval(r_in, rec, &scattered)
+            }
+            _ => Color::new_zero(),
+        }
+    }
+
+    // Fraction of the light travelling distance along direction from this vertex that isn't
+    // absorbed or scattered away by the medium on that side of it
+    fn transmittance(&self, direction: &Vec3, distance: f64) -> Color {
+        let mut medium = self.medium.clone();
+        if let Some(rec) = &self.rec {
+            medium.cross(rec, &Ray::new_timed(self.p, *direction, self.time));
+        }
+        medium.shadow_transmittance(distance)
+    }
+
+    // Radiance emitted from this vertex towards the point to
+    fn le(&self, to: &Point3) -> Color {
+        match self.facing(to) {
+            Some((rec, r)) => rec.mat.emitted(&r, &rec, rec.u, rec.v, &rec.p),
+            None => Color::new_zero(),
+        }
+    }
+
+    // pdf per unit area at next of scattering there from this vertex, having arrived from prev
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match (self.kind, prev) {
+            (VertexKind::Light, _) => self.pdf_light(next),
+            (VertexKind::Surface, Some(prev)) => {
+                let pdf = self.pdf_dir(&prev.p, &(next.p - self.p));
+                self.convert_density(pdf, next)
+            }
+            _ => 0.,
+        }
+    }
+
+    // pdf per unit solid angle of scattering along direction, having arrived from the point from
+    fn pdf_dir(&self, from: &Point3, direction: &Vec3) -> f64 {
+        match self.facing(from) {
+            Some((rec, r)) => {
+                let scattered = Ray::new_timed(self.p, *direction, r.time());
+                self.medium.scattering_pdf(&r, &rec, &scattered)
+            }
+            None => 0.,
+        }
+    }
+
+    // pdf per unit area at next of a light emitting from this vertex towards it
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let cosine = dot(&self.outward_normal(), &unit_vector(&(next.p - self.p)));
+        let two_sided = matches!(&self.rec, Some(rec) if rec.mat.is_two_sided());
+
+        if two_sided {
+            self.convert_density(cosine.abs() / (2. * PI), next)
+        } else if cosine > 0. {
+            self.convert_density(cosine / PI, next)
+        } else {
+            0.
+        }
+    }
+
+    // pdf per unit area of sampling this vertex as the start of a light subpath
+    fn pdf_light_origin(&self, scene: &Scene, from: &Point3) -> f64 {
+        scene.lights.surface_pdf(from, &self.p)
+    }
+}
+
+fn unoccluded(scene: &Scene, a: &Point3, b: &Point3, time: f64) -> bool {
+    scene
+        .world
+        .hit(
+            &Ray::new_timed(*a, *b - *a, time),
+            &Interval {
+                min: 0.0001,
+                max: 0.9999,
+            },
+        )
+        .is_none()
+}
+
+fn is_black(c: &Color) -> bool {
+    c.x() == 0. && c.y() == 0. && c.z() == 0.
+}
+
+// Extends path by following r, returning whatever radiance escapes the scene
+fn random_walk<'a>(
+    scene: &'a Scene,
+    r: &Ray,
+    beta: Color,
+    pdf_dir: f64,
+    path: &mut Vec<Vertex<'a>>,
+    from_camera: bool,
+) -> Color {
+    let mut r = r.clone();
+    let mut beta = beta;
+    let mut pdf_fwd = pdf_dir;
+    let mut medium = PathMedium::default();
+
+    for _ in 0..scene.cam.max_depth {
+        let (hit, transmittance) = medium.hit(scene, &r);
+        beta = beta * transmittance;
+
+        let rec = match hit {
+            Some(rec) => rec,
+            None if from_camera => return beta * escaped(scene, &r, path.len(), pdf_fwd),
+            None => break,
+        };
+
+        let prev = path.len() - 1;
+        let mut vertex = Vertex::surface(rec, &r, beta, medium.clone());
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+
+        let current = prev + 1;
+        let rec = path[current].rec.clone().expect("surface vertices have a hit record");
+
+        let srec = match medium.scatter(&r, &rec) {
+            Some(srec) => srec,
+            None => break,
+        };
+
+        let pdf_rev = match srec.data {
+            SrecData::SkipRay(skip_ray) => {
+                path[current].delta = true;
+                beta = beta * srec.attenuation;
+                pdf_fwd = 0.;
+                medium.cross(&rec, &skip_ray);
+                r = skip_ray;
+                0.
+            }
+            SrecData::PdfPtr(pdf_ptr) => {
+                let scattered = Ray::new_timed(rec.p, pdf_ptr.generate(), r.time());
+                pdf_fwd = pdf_ptr.value(&scattered.direction());
+
+                let f = medium.eval(&r, &rec, &scattered);
+                if pdf_fwd <= 0. || is_black(&f) {
+                    break;
+                }
+
+                beta = beta * f / pdf_fwd;
+                let pdf_rev = path[current].pdf_dir(&scattered.at(1.), &-r.direction());
+                medium.cross(&rec, &scattered);
+                r = scattered;
+                pdf_rev
+            }
+        };
+
+        let rev = path[current].convert_density(pdf_rev, &path[prev]);
+        path[prev].pdf_rev = rev;
+    }
+
+    Color::new_zero()
+}
+
+// The background seen by a camera path escaping along r from its len-th vertex, which it scattered
+// towards with pdf. Where the sun could have been sampled instead this is weighted against that.
+fn escaped(scene: &Scene, r: &Ray, len: usize, pdf: f64) -> Color {
+    let background = scene.cam.background.value(r);
+
+    match scene.cam.background.sun() {
+        // Straight from the camera or off a mirror nothing else reaches the sun
+        Some(sun) if len > 1 && pdf > 0. => {
+            let pdf_sun = sun.pdf().value(&r.direction());
+            background * pdf / (pdf + pdf_sun)
+        }
+        _ => background,
+    }
+}
+
+// Light from the sun scattered at pt, sampled from its disc and weighted against camera paths
+// escaping towards it
+fn connect_sun(scene: &Scene, camera: &[Vertex], t: usize) -> Color {
+    let pt = &camera[t - 1];
+    let sun = match scene.cam.background.sun() {
+        Some(sun) if !pt.delta && pt.kind == VertexKind::Surface => sun,
+        _ => return Color::new_zero(),
+    };
+
+    let sun_pdf = sun.pdf();
+    let direction = sun_pdf.generate();
+    let pdf_sun = sun_pdf.value(&direction);
+    if pdf_sun <= 0. {
+        return Color::new_zero();
+    }
+
+    let f = pt.f_dir(&direction);
+    if is_black(&f) {
+        return f;
+    }
+
+    let r = Ray::new_timed(pt.p, direction, pt.time);
+    let blocked = scene.world.hit(
+        &r,
+        &Interval {
+            min: 0.0001,
+            max: INF,
+        },
+    );
+    if blocked.is_some() {
+        return Color::new_zero();
+    }
+
+    let pdf_scatter = pt.pdf_dir(&camera[t - 2].p, &direction);
+    let le = scene.cam.background.value(&r) * pt.transmittance(&direction, INF);
+    pt.beta * f * le / (pdf_sun + pdf_scatter)
+}
+
+fn light_path<'a>(scene: &'a Scene, time: f64, path: &mut Vec<Vertex<'a>>) {
+    let (rec, pdf_pos) = match scene.lights.sample_surface(time) {
+        Some(sample) => sample,
+        None => return,
+    };
+
+    // Two sided lights emit from either side with equal probability
+    let (normal, sides) = match rec.mat.is_two_sided() {
+        true if random_double() < 0.5 => (-rec.normal, 2.),
+        true => (rec.normal, 2.),
+        false => (rec.normal, 1.),
+    };
+
+    let mut uvw = Onb::default();
+    uvw.build_from_w(&normal);
+    let direction = uvw.local_vec(&random_cosine_direction());
+    let pdf_dir = dot(&unit_vector(&direction), &normal) / (PI * sides);
+
+    let r = Ray::new_timed(rec.p, direction, time);
+    // Emission depends on direction for profiled lights, so look at the light from where the path goes
+    let toward = Ray::new_timed(r.at(1.), -direction, time);
+    let facing = rec.set_face_normal(&toward, &rec.normal);
+    let le = facing.mat.emitted(&toward, &facing, rec.u, rec.v, &rec.p);
+    if pdf_pos <= 0. || pdf_dir <= 0. || is_black(&le) {
+        return;
+    }
+
+    path.push(Vertex::light(rec, time, le, pdf_pos));
+
+    // The cosine at the light cancels with the cosine weighted direction pdf
+    let beta = le * PI * sides / pdf_pos;
+    random_walk(scene, &r, beta, pdf_dir, path, false);
+}
+
+// Weight of the strategy using s light and t camera vertices against all the others
+// that could have made the same path
+fn mis_weight(
+    scene: &Scene,
+    light: &[Vertex],
+    camera: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    if s + t == 2 {
+        return 1.;
+    }
+
+    // (pdf_fwd, pdf_rev, delta) of every vertex, updated for this connection
+    let mut cam: Vec<(f64, f64, bool)> =
+        camera[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+    let mut lig: Vec<(f64, f64, bool)> =
+        light[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+
+    let qs = match sampled {
+        Some(v) => {
+            lig[s - 1] = (v.pdf_fwd, v.pdf_rev, v.delta);
+            Some(v)
+        }
+        None if s > 0 => Some(&light[s - 1]),
+        None => None,
+    };
+    let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+    let pt = &camera[t - 1];
+    let pt_minus = &camera[t - 2];
+
+    match qs {
+        Some(qs) => {
+            cam[t - 1].1 = qs.pdf(qs_minus, pt);
+            cam[t - 2].1 = pt.pdf(Some(qs), pt_minus);
+            lig[s - 1].1 = pt.pdf(Some(pt_minus), qs);
+            lig[s - 1].2 = false;
+            if let Some(qs_minus) = qs_minus {
+                lig[s - 2].1 = qs.pdf(Some(pt), qs_minus);
+            }
+        }
+        None => {
+            cam[t - 1].1 = pt.pdf_light_origin(scene, &pt_minus.p);
+            cam[t - 2].1 = pt.pdf_light(pt_minus);
+        }
+    }
+    cam[t - 1].2 = false;
+
+    let remap = |f: f64| if f != 0. { f } else { 1. };
+    let mut sum_ri = 0.;
+
+    let mut ri = 1.;
+    for i in (2..t).rev() {
+        ri *= remap(cam[i].1) / remap(cam[i].0);
+        if !cam[i].2 && !cam[i - 1].2 {
+            sum_ri += ri;
+        }
+    }
+
+    let mut ri = 1.;
+    for i in (0..s).rev() {
+        ri *= remap(lig[i].1) / remap(lig[i].0);
+        let delta_light = i > 0 && lig[i - 1].2;
+        if !lig[i].2 && !delta_light {
+            sum_ri += ri;
+        }
+    }
+
+    1. / (1. + sum_ri)
+}
+
+fn connect(scene: &Scene, light: &[Vertex], camera: &[Vertex], s: usize, t: usize) -> Color {
+    let pt = &camera[t - 1];
+    let mut sampled = None;
+
+    let radiance = if s == 0 {
+        // The camera subpath found a light on its own
+        if pt.kind != VertexKind::Surface {
+            return Color::new_zero();
+        }
+        pt.beta * pt.le(&camera[t - 2].p)
+    } else if s == 1 {
+        // Pick a fresh point on a light, as in next event estimation
+        if pt.delta {
+            return Color::new_zero();
+        }
+
+        let (rec, pdf_pos) = match scene.lights.sample_surface(pt.time) {
+            Some(sample) => sample,
+            None => return Color::new_zero(),
+        };
+        if pdf_pos <= 0. {
+            return Color::new_zero();
+        }
+
+        let mut light_vertex = Vertex::light(rec, pt.time, Color::new_zero(), pdf_pos);
+        let le = light_vertex.le(&pt.p);
+        light_vertex.beta = le / pdf_pos;
+
+        let w = pt.p - light_vertex.p;
+        let cos_light = dot(&light_vertex.outward_normal(), &unit_vector(&w)).abs();
+        let radiance = pt.beta * pt.f(&light_vertex) * light_vertex.beta * cos_light
+            / w.length_squared()
+            * pt.transmittance(&-w, w.length());
+
+        if is_black(&radiance) || !unoccluded(scene, &pt.p, &light_vertex.p, pt.time) {
+            return Color::new_zero();
+        }
+
+        sampled = Some(light_vertex);
+        radiance
+    } else {
+        let qs = &light[s - 1];
+        if qs.delta || pt.delta {
+            return Color::new_zero();
+        }
+
+        let w = qs.p - pt.p;
+        let dist_squared = w.length_squared();
+        let radiance = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta / dist_squared
+            * pt.transmittance(&w, dist_squared.sqrt());
+
+        if is_black(&radiance) || !unoccluded(scene, &pt.p, &qs.p, pt.time) {
+            return Color::new_zero();
+        }
+        radiance
+    };
+
+    if is_black(&radiance) {
+        return radiance;
+    }
+
+    radiance * mis_weight(scene, light, camera, sampled.as_ref(), s, t)
+}
+
+// Light from the delta lights scattered at pt. Camera subpaths can't hit them and light subpaths
+// only start on scene.lights, so no other strategy makes these paths and they need no weighting.
+fn connect_delta_lights(scene: &Scene, pt: &Vertex) -> Color {
+    match (&pt.rec, &pt.r_in) {
+        (Some(rec), Some(r_in)) if !pt.delta => {
+            pt.beta * sample_delta_lights(r_in, rec, scene, &pt.medium)
+        }
+        _ => Color::new_zero(),
+    }
+}
+
+impl Integrate for Bdpt {
+
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        let max_depth = scene.cam.max_depth as usize;
+
+        let mut camera = vec![Vertex::camera(r)];
+        let escaped = random_walk(scene, r, Color::new(1., 1., 1.), 1., &mut camera, true);
+
+        let mut light = vec![];
+        light_path(scene, r.time(), &mut light);
+
+        let mut radiance = escaped;
+        for t in 2..=camera.len() {
+            if t - 1 <= max_depth {
+                radiance = radiance
+                    + connect_delta_lights(scene, &camera[t - 1])
+                    + connect_sun(scene, &camera, t);
+            }
+
+            for s in 0..=light.len().max(1) {
+                if s + t - 2 > max_depth {
+                    break;
+                }
+                radiance = radiance + connect(scene, &light, &camera, s, t);
+            }
+        }
+
+        radiance
+    }
+}
@@ -58,3 +58,7 @@ fn linear_to_gamma(linear: f64) -> f64 {
     }
 }
 
+
+pub fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
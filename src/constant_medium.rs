@@ -4,7 +4,8 @@ use crate::{
     color::Color,
     hittable::{HitRecord, Hittable},
     interval::{Interval, _UNIVERSE},
-    material::{Isotropic, Material},
+    material::{Anisotropic, EmissiveVolume, Isotropic, Material},
+    phase::PhaseFunction,
     object::Object,
     ray::Ray,
     texture::Texture,
@@ -28,6 +29,24 @@ impl ConstantMedium {
         })
     }
 
+    // A medium that scatters with phase instead of equally in every direction
+    pub fn with_phase(boundary: Arc<Object>, density: f64, c: Color, phase: PhaseFunction) -> Object {
+        Object::Volume(ConstantMedium {
+            boundary,
+            neg_inv_density: -1. / density,
+            phase_function: Anisotropic::new(c, phase),
+        })
+    }
+
+    // A glowing medium, see EmissiveVolume
+    pub fn emissive(boundary: Arc<Object>, density: f64, c: Color, emit: Arc<Texture>) -> Object {
+        Object::Volume(ConstantMedium {
+            boundary,
+            neg_inv_density: -1. / density,
+            phase_function: EmissiveVolume::new(Isotropic::new(c), emit),
+        })
+    }
+
     pub fn _from_texture(boundary: Arc<Object>, density: f64, albedo: Arc<Texture>) -> Object {
         Object::Volume(ConstantMedium {
             boundary,
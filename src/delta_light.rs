@@ -0,0 +1,277 @@
+use std::{f64::consts::PI, sync::Arc};
+
+use crate::{
+    color::Color,
+    ies::IesProfile,
+    photometry::{chromaticity, LightUnit},
+    utils::INF,
+    vec3::{dot, unit_vector, Point3, Vec3},
+};
+
+// Lights without any area, which rays can never hit and are only found by shadow rays
+#[derive(Clone)]
+pub enum DeltaLight {
+    Point(PointLight),
+    Spot(SpotLight),
+    Directional(DirectionalLight),
+}
+
+// Where a delta light is as seen from a shading point
+pub struct LightSample {
+    pub direction: Vec3, // unit vector towards the light
+    pub distance: f64,   // INF for lights infinitely far away
+    pub radiance: Color, // light arriving at the shading point, before any cosine
+}
+
+impl DeltaLight {
+    pub fn sample(&self, p: &Point3) -> Option<LightSample> {
+        match self {
+            DeltaLight::Point(l) => l.sample(p),
+            DeltaLight::Spot(l) => l.sample(p),
+            DeltaLight::Directional(l) => l.sample(p),
+        }
+    }
+
+    // Puts the light in a light group, whose contribution is written to its own image when
+    // rendering light groups
+    pub fn in_group(self, group: &str) -> DeltaLight {
+        let group = Some(group.to_string());
+        match self {
+            DeltaLight::Point(l) => DeltaLight::Point(PointLight { group, ..l }),
+            DeltaLight::Spot(l) => DeltaLight::Spot(SpotLight { group, ..l }),
+            DeltaLight::Directional(l) => DeltaLight::Directional(DirectionalLight { group, ..l }),
+        }
+    }
+
+    // Name of the light group the light belongs to, None for the default group
+    pub fn group(&self) -> Option<&str> {
+        match self {
+            DeltaLight::Point(l) => l.group.as_deref(),
+            DeltaLight::Spot(l) => l.group.as_deref(),
+            DeltaLight::Directional(l) => l.group.as_deref(),
+        }
+    }
+}
+
+impl From<PointLight> for DeltaLight {
+    fn from(l: PointLight) -> DeltaLight {
+        DeltaLight::Point(l)
+    }
+}
+
+impl From<SpotLight> for DeltaLight {
+    fn from(l: SpotLight) -> DeltaLight {
+        DeltaLight::Spot(l)
+    }
+}
+
+impl From<DirectionalLight> for DeltaLight {
+    fn from(l: DirectionalLight) -> DeltaLight {
+        DeltaLight::Directional(l)
+    }
+}
+
+// How much of a light's intensity leaves towards direction
+fn profile_value(profile: &Option<Arc<IesProfile>>, direction: &Vec3, nadir: &Vec3) -> f64 {
+    match profile {
+        Some(profile) => profile.value(direction, nadir),
+        None => 1.,
+    }
+}
+
+// Direction and distance from p to position, along with the inverse square falloff
+fn towards(p: &Point3, position: &Point3) -> Option<(Vec3, f64, f64)> {
+    let to_light = *position - *p;
+    let distance_squared = to_light.length_squared();
+    if distance_squared <= 0. {
+        return None;
+    }
+
+    let distance = distance_squared.sqrt();
+    Some((to_light / distance, distance, 1. / distance_squared))
+}
+
+// Shines intensity equally in every direction
+#[derive(Clone)]
+pub struct PointLight {
+    position: Point3,
+    intensity: Color,
+    profile: Option<Arc<IesProfile>>,
+    group: Option<String>,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, intensity: Color) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+            profile: None,
+            group: None,
+        }
+    }
+
+    // A bulb of color (see photometry::chromaticity) as bright as unit, shining in every direction
+    pub fn photometric(position: Point3, color: Color, unit: LightUnit) -> PointLight {
+        PointLight::new(position, chromaticity(&color) * unit.candela(4. * PI))
+    }
+
+    // A fixture shaped by its photometric profile, giving off the profile's rated lumens, or the
+    // candela it was measured at for absolute photometry
+    pub fn from_ies(position: Point3, color: Color, profile: Arc<IesProfile>) -> PointLight {
+        let candela = match profile.lumens() {
+            Some(lumens) => LightUnit::Lumens(lumens).candela(profile.solid_angle()),
+            None => profile.peak_candela(),
+        };
+        PointLight::new(position, chromaticity(&color) * candela).with_profile(profile)
+    }
+
+    // Shapes the light by a fixture's photometric profile, hanging with its nadir straight down
+    pub fn with_profile(self, profile: Arc<IesProfile>) -> PointLight {
+        PointLight {
+            profile: Some(profile),
+            ..self
+        }
+    }
+
+    fn sample(&self, p: &Point3) -> Option<LightSample> {
+        let (direction, distance, falloff) = towards(p, &self.position)?;
+        let shape = profile_value(&self.profile, &-direction, &Vec3::new(0., -1., 0.));
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.intensity * falloff * shape,
+        })
+    }
+}
+
+// A point light limited to a cone around the direction it points in. Full intensity reaches out
+// to falloff_start degrees from the axis, fading smoothly to nothing at cone_angle degrees.
+#[derive(Clone)]
+pub struct SpotLight {
+    position: Point3,
+    axis: Vec3,
+    intensity: Color,
+    cos_cone: f64,
+    cos_falloff_start: f64,
+    profile: Option<Arc<IesProfile>>,
+    group: Option<String>,
+}
+
+impl SpotLight {
+    pub fn new(
+        position: Point3,
+        target: Point3,
+        intensity: Color,
+        cone_angle: f64,
+        falloff_start: f64,
+    ) -> SpotLight {
+        let falloff_start = falloff_start.min(cone_angle);
+
+        SpotLight {
+            position,
+            axis: unit_vector(&(target - position)),
+            intensity,
+            cos_cone: cone_angle.to_radians().cos(),
+            cos_falloff_start: falloff_start.to_radians().cos(),
+            profile: None,
+            group: None,
+        }
+    }
+
+    // A spot light of color (see photometry::chromaticity) as bright as unit. Flux only counts
+    // the light inside the cone, so narrowing the cone makes the same lumens brighter.
+    pub fn photometric(
+        position: Point3,
+        target: Point3,
+        color: Color,
+        unit: LightUnit,
+        cone_angle: f64,
+        falloff_start: f64,
+    ) -> SpotLight {
+        let spot = SpotLight::new(
+            position,
+            target,
+            Color::new_zero(),
+            cone_angle,
+            falloff_start,
+        );
+        let intensity = chromaticity(&color) * unit.candela(spot.solid_angle());
+        SpotLight { intensity, ..spot }
+    }
+
+    // Shapes the light by a fixture's photometric profile, with its nadir along the axis
+    pub fn with_profile(self, profile: Arc<IesProfile>) -> SpotLight {
+        SpotLight {
+            profile: Some(profile),
+            ..self
+        }
+    }
+
+    // Solid angle of the cone, counting the falloff region by how much light gets there
+    fn solid_angle(&self) -> f64 {
+        let steps = 256;
+        let step = (1. - self.cos_cone) / steps as f64;
+
+        (0..steps)
+            .map(|i| self.falloff(self.cos_cone + (i as f64 + 0.5) * step))
+            .sum::<f64>()
+            * step
+            * 2.
+            * PI
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_falloff_start {
+            1.
+        } else if cos_theta <= self.cos_cone {
+            0.
+        } else {
+            let t = (cos_theta - self.cos_cone) / (self.cos_falloff_start - self.cos_cone);
+            t * t * (3. - 2. * t)
+        }
+    }
+
+    fn sample(&self, p: &Point3) -> Option<LightSample> {
+        let (direction, distance, falloff) = towards(p, &self.position)?;
+
+        let cone = self.falloff(dot(&-direction, &self.axis))
+            * profile_value(&self.profile, &-direction, &self.axis);
+        if cone <= 0. {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.intensity * falloff * cone,
+        })
+    }
+}
+
+// Parallel light from infinitely far away, like the sun without its disk
+#[derive(Clone)]
+pub struct DirectionalLight {
+    direction: Vec3, // towards the light
+    irradiance: Color,
+    group: Option<String>,
+}
+
+impl DirectionalLight {
+    // direction points from the scene towards the light
+    pub fn new(direction: Vec3, irradiance: Color) -> DirectionalLight {
+        DirectionalLight {
+            direction: unit_vector(&direction),
+            irradiance,
+            group: None,
+        }
+    }
+
+    fn sample(&self, _p: &Point3) -> Option<LightSample> {
+        Some(LightSample {
+            direction: self.direction,
+            distance: INF,
+            radiance: self.irradiance,
+        })
+    }
+}
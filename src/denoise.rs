@@ -0,0 +1,190 @@
+use rayon::prelude::*;
+
+use crate::{
+    color::{luminance, Color},
+    integrator::Scene,
+    render::get_ray_raster,
+    utils::random_double,
+    vec3::{dot, Vec3},
+};
+
+// Camera rays averaged per pixel for the guide buffers
+const GUIDE_SAMPLES: i32 = 8;
+
+// Each pass doubles the gap between the taps of the 5x5 kernel, five passes cover 61x61 pixels
+const PASSES: u32 = 5;
+const KERNEL: [f64; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+
+const SIGMA_COLOR: f64 = 0.6;
+const SIGMA_NORMAL: f64 = 0.1;
+const SIGMA_ALBEDO: f64 = 0.1;
+const SIGMA_DEPTH: f64 = 0.05;
+
+// Noise free features of the first surface seen through every pixel
+pub struct Guides {
+    albedo: Vec<Color>,
+    normal: Vec<Vec3>,
+    depth: Vec<f64>,
+}
+
+impl Guides {
+    pub fn new(scene: &Scene, width: usize, height: usize) -> Guides {
+        let features: Vec<(Color, Vec3, f64)> = (0..width * height)
+            .into_par_iter()
+            .map(|idx| {
+                let (x, y) = ((idx % width) as f64, (idx / width) as f64);
+
+                let mut albedo = Color::new_zero();
+                let mut normal = Vec3::new_zero();
+                let mut depth = 0.;
+
+                for _ in 0..GUIDE_SAMPLES {
+                    let r = get_ray_raster(scene.cam, x + random_double(), y + random_double());
+                    if let Some(rec) = scene.hit(&r) {
+                        albedo = albedo + rec.mat.albedo(&rec);
+                        // Volumes have no real normal, leave them at zero so they blur freely
+                        if !rec.mat.is_volumetric() {
+                            normal = normal + rec.normal;
+                        }
+                        depth += rec.t * r.direction().length();
+                    } else {
+                        // Escaping rays see the background, which has no texture of its own
+                        albedo = albedo + Color::new(1., 1., 1.);
+                    }
+                }
+
+                let n = GUIDE_SAMPLES as f64;
+                (albedo / n, normal / n, depth / n)
+            })
+            .collect();
+
+        let max_depth = features.iter().map(|f| f.2).fold(0., f64::max).max(1e-8);
+
+        Guides {
+            albedo: features.iter().map(|f| f.0).collect(),
+            normal: features.iter().map(|f| f.1).collect(),
+            depth: features.iter().map(|f| f.2 / max_depth).collect(),
+        }
+    }
+}
+
+// Edge avoiding à-trous wavelet filter (Dammertz et al.). pixels hold sums of samples_per_pixel
+// samples and are filtered in place. Lighting is divided by the albedo before filtering and
+// multiplied back after, so textures stay sharp while the noise in between is smoothed away.
+// layers, the light groups pixels is the sum of, are filtered with the same weights as pixels so
+// they still add up to it.
+pub fn denoise(
+    pixels: &mut [Color],
+    layers: &mut [Vec<Color>],
+    samples_per_pixel: f64,
+    guides: &Guides,
+    width: usize,
+) {
+    let height = pixels.len() / width;
+
+    let demodulated = |image: &[Color]| -> Vec<Color> {
+        image
+            .iter()
+            .zip(guides.albedo.iter())
+            .map(|(c, a)| demodulate(&(*c / samples_per_pixel), a))
+            .collect()
+    };
+    let mut irradiance = demodulated(pixels);
+    let mut layer_irradiance: Vec<Vec<Color>> = layers.iter().map(|l| demodulated(l)).collect();
+
+    for pass in 0..PASSES {
+        let step = 1_i64 << pass;
+        // Later passes compare colors that have already been smoothed, so they can be stricter
+        let sigma_color = SIGMA_COLOR / (1 << pass) as f64;
+
+        let filtered: Vec<(Color, Vec<Color>)> = (0..pixels.len())
+            .into_par_iter()
+            .map(|idx| {
+                let (x, y) = ((idx % width) as i64, (idx / width) as i64);
+                let c_p = irradiance[idx];
+
+                let mut sum = Color::new_zero();
+                let mut layer_sums = vec![Color::new_zero(); layer_irradiance.len()];
+                let mut weight_sum = 0.;
+
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (i as i64 - 2) * step;
+                        let qy = y + (j as i64 - 2) * step;
+                        if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+
+                        let w = kx * ky * edge_weight(idx, q, &c_p, &irradiance[q], guides, sigma_color);
+                        sum = sum + w * irradiance[q];
+                        for (layer_sum, layer) in layer_sums.iter_mut().zip(&layer_irradiance) {
+                            *layer_sum = *layer_sum + w * layer[q];
+                        }
+                        weight_sum += w;
+                    }
+                }
+
+                if weight_sum > 0. {
+                    (
+                        sum / weight_sum,
+                        layer_sums.into_iter().map(|c| c / weight_sum).collect(),
+                    )
+                } else {
+                    (c_p, layer_irradiance.iter().map(|layer| layer[idx]).collect())
+                }
+            })
+            .collect();
+
+        for (idx, (c, layer_cs)) in filtered.into_iter().enumerate() {
+            irradiance[idx] = c;
+            for (layer, c) in layer_irradiance.iter_mut().zip(layer_cs) {
+                layer[idx] = c;
+            }
+        }
+    }
+
+    for ((pixel, c), a) in pixels.iter_mut().zip(irradiance).zip(guides.albedo.iter()) {
+        *pixel = remodulate(&c, a) * samples_per_pixel;
+    }
+    for (layer, filtered) in layers.iter_mut().zip(layer_irradiance) {
+        for ((pixel, c), a) in layer.iter_mut().zip(filtered).zip(guides.albedo.iter()) {
+            *pixel = remodulate(&c, a) * samples_per_pixel;
+        }
+    }
+}
+
+fn edge_weight(p: usize, q: usize, c_p: &Color, c_q: &Color, guides: &Guides, sigma_color: f64) -> f64 {
+    // Compare colors after compressing their range so a single bright sample doesn't block all smoothing
+    let color_distance = (compress(c_p) - compress(c_q)).length_squared();
+    let w_color = (-color_distance / (sigma_color * sigma_color)).exp();
+
+    let w_normal = (-(1. - dot(&guides.normal[p], &guides.normal[q])).max(0.) / SIGMA_NORMAL).exp();
+
+    let albedo_distance = (guides.albedo[p] - guides.albedo[q]).length_squared();
+    let w_albedo = (-albedo_distance / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+
+    let w_depth = (-(guides.depth[p] - guides.depth[q]).abs() / SIGMA_DEPTH).exp();
+
+    w_color * w_normal * w_albedo * w_depth
+}
+
+fn compress(c: &Color) -> Color {
+    *c / (1. + luminance(c).max(0.))
+}
+
+fn demodulate(c: &Color, albedo: &Color) -> Color {
+    Color::new(
+        c.x() / albedo.x().max(0.01),
+        c.y() / albedo.y().max(0.01),
+        c.z() / albedo.z().max(0.01),
+    )
+}
+
+fn remodulate(c: &Color, albedo: &Color) -> Color {
+    Color::new(
+        c.x() * albedo.x().max(0.01),
+        c.y() * albedo.y().max(0.01),
+        c.z() * albedo.z().max(0.01),
+    )
+}
@@ -0,0 +1,187 @@
+// A discrete distribution proportional to a list of non-negative weights
+#[derive(Clone)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    // All zero (or empty) weights fall back to picking uniformly
+    pub fn new(func: Vec<f64>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].max(0.);
+        }
+
+        let integral = cdf[n];
+        if integral > 0. {
+            cdf.iter_mut().for_each(|c| *c /= integral);
+        } else {
+            cdf.iter_mut()
+                .enumerate()
+                .for_each(|(i, c)| *c = i as f64 / n as f64);
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Index picked by u in [0, 1) and the probability of picking it
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let i = self
+            .cdf
+            .partition_point(|c| *c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+
+        (i, self.discrete_pmf(i))
+    }
+
+    pub fn discrete_pmf(&self, i: usize) -> f64 {
+        self.cdf[i + 1] - self.cdf[i]
+    }
+
+    // Treating the weights as a step function over [0, 1), the point picked by u in [0, 1)
+    // and the density there
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64) {
+        let (i, pmf) = self.sample_discrete(u);
+
+        let offset = if pmf > 0. {
+            ((u - self.cdf[i]) / pmf).clamp(0., 1.)
+        } else {
+            0.5
+        };
+
+        let n = self.count() as f64;
+        ((i as f64 + offset) / n, pmf * n)
+    }
+}
+
+// A piecewise constant density over [0, 1) x [0, 1) proportional to a grid of nu by nv weights,
+// sampled by picking a row from the marginal distribution and then a cell within the row
+#[derive(Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // func is laid out a row of nu weights at a time
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        assert_eq!(func.len(), nu * nv, "Weights do not match the grid size");
+
+        let conditional: Vec<Distribution1D> = func
+            .chunks(nu)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    // Mean of the weights
+    pub fn average(&self) -> f64 {
+        let nu = self.conditional[0].count();
+        self.marginal.integral() / (nu * self.marginal.count()) as f64
+    }
+
+    // A point picked by (u1, u2) in [0, 1)^2 and the density of picking it
+    pub fn sample_continuous(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v) = self.marginal.sample_continuous(u2);
+        let row = (v * self.marginal.count() as f64) as usize;
+        let (u, pdf_u) = self.conditional[row.min(self.marginal.count() - 1)].sample_continuous(u1);
+
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nv = self.marginal.count();
+        let row = ((v * nv as f64) as usize).min(nv - 1);
+        let conditional = &self.conditional[row];
+        let nu = conditional.count();
+        let column = ((u * nu as f64) as usize).min(nu - 1);
+
+        self.marginal.discrete_pmf(row) * nv as f64 * conditional.discrete_pmf(column) * nu as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn sample_continuous_matches_the_weights() {
+        let weights = vec![1., 0., 3., 4.];
+        let d = Distribution1D::new(weights.clone());
+
+        for k in 0..1000 {
+            let (x, pdf) = d.sample_continuous(k as f64 / 1000.);
+            let cell = (x * 4.) as usize;
+            assert!((0. ..1.).contains(&x));
+            assert_ne!(cell, 1, "zero weight cells are never picked");
+            assert!(close(pdf, weights[cell] / d.integral() * 4.));
+        }
+    }
+
+    #[test]
+    fn density_integrates_to_one() {
+        let d = Distribution1D::new(vec![0.5, 2., 0., 1.5, 6.]);
+        let integral: f64 = (0..d.count()).map(|i| d.discrete_pmf(i)).sum();
+        assert!(close(integral, 1.));
+
+        let empty = Distribution1D::new(vec![0., 0., 0.]);
+        let (x, pdf) = empty.sample_continuous(0.7);
+        assert!(close(pdf, 1.));
+        assert!((2. / 3. ..1.).contains(&x));
+    }
+
+    #[test]
+    fn sample_continuous_2d_agrees_with_pdf() {
+        let (nu, nv) = (5, 3);
+        let weights: Vec<f64> = (0..nu * nv).map(|i| (i % 4) as f64).collect();
+        let d = Distribution2D::new(&weights, nu, nv);
+
+        for i in 0..40 {
+            for j in 0..40 {
+                let ((u, v), pdf) = d.sample_continuous(i as f64 / 40., j as f64 / 40.);
+                assert!(pdf > 0.);
+                assert!(close(pdf, d.pdf(u, v)));
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_2d_integrates_to_one() {
+        let (nu, nv) = (4, 6);
+        let weights: Vec<f64> = (0..nu * nv).map(|i| 1. + (i * 7 % 5) as f64).collect();
+        let d = Distribution2D::new(&weights, nu, nv);
+
+        let cells = (nu * nv) as f64;
+        let integral: f64 = (0..nv)
+            .flat_map(|j| (0..nu).map(move |i| (i, j)))
+            .map(|(i, j)| d.pdf((i as f64 + 0.5) / nu as f64, (j as f64 + 0.5) / nv as f64) / cells)
+            .sum();
+        assert!(close(integral, 1.));
+        assert!(close(d.average(), weights.iter().sum::<f64>() / cells));
+    }
+}
@@ -0,0 +1,248 @@
+use std::sync::Arc;
+
+use crate::{
+    color::Color,
+    hittable::{HitRecord, Hittable},
+    interval::{Interval, _UNIVERSE},
+    material::{Anisotropic, EmissiveVolume, Isotropic, Material},
+    phase::PhaseFunction,
+    object::{Aabb, Object},
+    ray::Ray,
+    texture::Texture,
+    utils::{random_double, INF},
+    vec3::{Point3, Vec3},
+};
+
+// Densities sampled on a regular grid spanning bounds, interpolated trilinearly in between
+#[derive(Clone)]
+pub struct VoxelGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    data: Vec<f64>,
+    bounds: Aabb,
+}
+
+impl VoxelGrid {
+    // data is laid out x fastest, then y, then z
+    pub fn new(nx: usize, ny: usize, nz: usize, data: Vec<f64>, bounds: Aabb) -> VoxelGrid {
+        assert_eq!(data.len(), nx * ny * nz, "Voxel data does not match the grid size");
+
+        VoxelGrid {
+            nx,
+            ny,
+            nz,
+            data,
+            bounds,
+        }
+    }
+
+    // Fills the grid with f evaluated at the center of every voxel, in coordinates from 0 to 1 across bounds
+    pub fn from_fn<F>(nx: usize, ny: usize, nz: usize, bounds: Aabb, f: F) -> VoxelGrid
+    where
+        F: Fn(&Point3) -> f64,
+    {
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    data.push(f(&Point3::new(
+                        (x as f64 + 0.5) / nx as f64,
+                        (y as f64 + 0.5) / ny as f64,
+                        (z as f64 + 0.5) / nz as f64,
+                    )));
+                }
+            }
+        }
+
+        VoxelGrid::new(nx, ny, nz, data, bounds)
+    }
+
+    pub fn max(&self) -> f64 {
+        self.data.iter().copied().fold(0., f64::max)
+    }
+
+    fn voxel(&self, x: i64, y: i64, z: i64) -> f64 {
+        let x = x.clamp(0, self.nx as i64 - 1) as usize;
+        let y = y.clamp(0, self.ny as i64 - 1) as usize;
+        let z = z.clamp(0, self.nz as i64 - 1) as usize;
+        self.data[(z * self.ny + y) * self.nx + x]
+    }
+
+    pub fn density(&self, p: &Point3) -> f64 {
+        if !self.bounds.contains(p) {
+            return 0.;
+        }
+
+        // Continuous voxel coordinates, with voxel centers at whole numbers
+        let local = |axis: u8, n: usize| {
+            let interval = self.bounds.axis(axis);
+            (p.dim(axis) - interval.min) / interval.size() * n as f64 - 0.5
+        };
+        let (x, y, z) = (local(0, self.nx), local(1, self.ny), local(2, self.nz));
+
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (fx, fy, fz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+        let mut density = 0.;
+        for (i, wx) in [(0, 1. - fx), (1, fx)] {
+            for (j, wy) in [(0, 1. - fy), (1, fy)] {
+                for (k, wz) in [(0, 1. - fz), (1, fz)] {
+                    density += wx * wy * wz * self.voxel(x0 + i, y0 + j, z0 + k);
+                }
+            }
+        }
+        density
+    }
+}
+
+#[derive(Clone)]
+pub enum DensityField {
+    // The average of the texture's channels times scale
+    Texture { texture: Arc<Texture>, scale: f64 },
+    Grid(VoxelGrid),
+}
+
+impl DensityField {
+    fn density(&self, p: &Point3) -> f64 {
+        match self {
+            DensityField::Texture { texture, scale } => {
+                let c = texture.value(0., 0., p);
+                scale * (c.x() + c.y() + c.z()) / 3.
+            }
+            DensityField::Grid(grid) => grid.density(p),
+        }
+    }
+}
+
+// A volume whose density changes from point to point. Free flights are sampled with delta
+// tracking against max_density: tentative collisions are drawn as if the whole volume were at
+// max_density and only kept with probability density / max_density, which stays unbiased.
+#[derive(Clone)]
+pub struct HeterogeneousMedium {
+    boundary: Arc<Object>,
+    density: DensityField,
+    max_density: f64,
+    phase_function: Material,
+}
+
+impl HeterogeneousMedium {
+    // Density from a 3D texture such as NoiseTexture. max_density has to bound scale times the
+    // texture, anything above it is clamped.
+    pub fn from_texture(
+        boundary: Arc<Object>,
+        density: Arc<Texture>,
+        scale: f64,
+        max_density: f64,
+        c: Color,
+        phase: PhaseFunction,
+    ) -> Object {
+        Object::HeterogeneousVolume(HeterogeneousMedium {
+            boundary,
+            density: DensityField::Texture {
+                texture: density,
+                scale,
+            },
+            max_density,
+            phase_function: phase_material(c, phase),
+        })
+    }
+
+    // Fire and glowing gas, with density from a texture like from_texture and emit added at every
+    // collision, see EmissiveVolume
+    pub fn emissive(
+        boundary: Arc<Object>,
+        density: Arc<Texture>,
+        scale: f64,
+        max_density: f64,
+        c: Color,
+        emit: Arc<Texture>,
+    ) -> Object {
+        Object::HeterogeneousVolume(HeterogeneousMedium {
+            boundary,
+            density: DensityField::Texture {
+                texture: density,
+                scale,
+            },
+            max_density,
+            phase_function: EmissiveVolume::new(Isotropic::new(c), emit),
+        })
+    }
+
+    pub fn from_grid(
+        boundary: Arc<Object>,
+        grid: VoxelGrid,
+        c: Color,
+        phase: PhaseFunction,
+    ) -> Object {
+        Object::HeterogeneousVolume(HeterogeneousMedium {
+            boundary,
+            max_density: grid.max(),
+            density: DensityField::Grid(grid),
+            phase_function: phase_material(c, phase),
+        })
+    }
+
+    pub fn density(&self, p: &Point3) -> f64 {
+        self.density.density(p).clamp(0., self.max_density)
+    }
+}
+
+// Isotropic media keep the cheaper uniform sphere sampling
+fn phase_material(c: Color, phase: PhaseFunction) -> Material {
+    match phase {
+        PhaseFunction::Isotropic => Isotropic::new(c),
+        _ => Anisotropic::new(c, phase),
+    }
+}
+
+impl Hittable for HeterogeneousMedium {
+    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        if self.max_density <= 0. {
+            return None;
+        }
+
+        let rec1 = self.boundary.hit(r, &_UNIVERSE)?;
+        let rec2 = self.boundary.hit(
+            r,
+            &Interval {
+                min: rec1.t + 0.0001,
+                max: INF,
+            },
+        )?;
+
+        let t_min = rec1.t.max(ray_t.min).max(0.);
+        let t_max = rec2.t.min(ray_t.max);
+        if t_min >= t_max {
+            return None;
+        }
+
+        let ray_length = r.direction().length();
+        let mut t = t_min;
+
+        loop {
+            t -= (1. - random_double()).ln() / (self.max_density * ray_length);
+            if t >= t_max {
+                return None;
+            }
+
+            let p = r.at(t);
+            if random_double() * self.max_density < self.density(&p) {
+                return Some(HitRecord {
+                    t,
+                    p,
+                    normal: Vec3::new(1., 0., 0.), // arbitrary
+                    front_face: true,              // arbitrary
+                    mat: &self.phase_function,
+                    u: 0.,
+                    v: 0.,
+                });
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Option<&Aabb> {
+        self.boundary.bounding_box()
+    }
+}
@@ -8,6 +8,7 @@ use crate::ray::Ray;
 use crate::utils::random_int;
 use crate::vec3::{dot, Point3, Vec3};
 
+#[derive(Clone)]
 pub struct HitRecord<'material> {
     pub p: Point3,    // intersection point
     pub normal: Vec3, // normal at hit
@@ -50,6 +51,17 @@ pub trait Hittable {
     fn random(&self, origin: &Point3) -> Vec3 {
         Vec3::new(1., 0., 0.)
     }
+
+    // Picks a point on the surface, returning it with its outward normal and the pdf per unit area.
+    // Used to start paths on lights.
+    fn sample_surface(&self, _time: f64) -> Option<(HitRecord, f64)> {
+        None
+    }
+
+    // The pdf per unit area that sample_surface picks the point p, seen from origin
+    fn surface_pdf(&self, _origin: &Point3, _p: &Point3) -> f64 {
+        0.
+    }
 }
 
 pub struct HittableList {
@@ -79,6 +91,24 @@ impl HittableList {
         self.objects.push(object);
     }
 
+    // Every emitter in the list, to sample light from directly
+    pub fn collect_lights(&self) -> HittableList {
+        let mut lights = HittableList::new();
+        for object in self.objects.iter() {
+            object.collect_lights(&mut lights);
+        }
+        lights
+    }
+
+    // Names of the light groups used in the list, in the order they are first found
+    pub fn light_groups(&self) -> Vec<String> {
+        let mut groups = vec![];
+        for object in self.objects.iter() {
+            object.light_groups(&mut groups);
+        }
+        groups
+    }
+
     pub fn create_bvh(&mut self) -> HittableList {
         HittableList::from_object(Object::Node(Arc::new(BvhNode::from_list(self))))
     }
@@ -113,20 +143,53 @@ impl Hittable for HittableList {
     }
 
     fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.;
+        }
+
         let weight = 1. / self.objects.len() as f64;
 
         self.objects
             .iter()
-            .map(|obj| obj.pdf_value(origin, direction) )
-            .reduce(|x, y| x + y).expect("Could not sum pdf values of list")
+            .map(|obj| obj.pdf_value(origin, direction))
+            .sum::<f64>()
             * weight
-
     }
 
+    // Empty lists have nothing to aim at, callers should check before sampling them
     fn random(&self, origin: &Point3) -> Vec3 {
+        if self.objects.is_empty() {
+            return Vec3::new(1., 0., 0.);
+        }
+
         let int_size = self.objects.len() as i64;
         self.objects[random_int(0, int_size - 1) as usize].random(origin)
     }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        if self.objects.is_empty() {
+            return None;
+        }
+
+        let int_size = self.objects.len() as i64;
+        let (rec, pdf) = self.objects[random_int(0, int_size - 1) as usize].sample_surface(time)?;
+
+        Some((rec, pdf / int_size as f64))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.;
+        }
+
+        let weight = 1. / self.objects.len() as f64;
+
+        self.objects
+            .iter()
+            .map(|obj| obj.surface_pdf(origin, p))
+            .sum::<f64>()
+            * weight
+    }
 }
 
 // In the book left and right are shared pointers,
@@ -158,8 +221,12 @@ impl BvhNode {
 
         let object_span = end - start;
 
+        // A single object goes on the left, an empty list on the right keeps it from being found twice
         let (left, right) = if object_span == 1 {
-            (objects[start].clone(), objects[start].clone())
+            (
+                objects[start].clone(),
+                Object::List(Arc::new(HittableList::new())),
+            )
         } else if object_span == 2 {
             match comparator(&objects[start], &objects[start + 1]) {
                 Ordering::Less => (objects[start].clone(), objects[start + 1].clone()),
@@ -186,6 +253,20 @@ impl BvhNode {
         }
     }
 
+    pub fn collect_lights(&self, lights: &mut HittableList) {
+        self.left.collect_lights(lights);
+        self.right.collect_lights(lights);
+    }
+
+    pub fn power(&self) -> f64 {
+        self.left.power() + self.right.power()
+    }
+
+    pub fn light_groups(&self, groups: &mut Vec<String>) {
+        self.left.light_groups(groups);
+        self.right.light_groups(groups);
+    }
+
     fn box_compare(a: &Object, b: &Object, axis: u8) -> Ordering {
         a.bounding_box()
             .expect("No bounding box for object a")
@@ -240,3 +321,75 @@ impl Hittable for BvhNode {
     }
 }
 
+
+// The same traversals as hit, also counting the BVH nodes tested against r in visits.
+// Only the BVH previews use these, so the normal hit stays as fast as it can be.
+impl Object {
+    pub fn hit_counting(
+        &self,
+        r: &Ray,
+        ray_t: &Interval,
+        visits: &mut u32,
+    ) -> Option<HitRecord<'_>> {
+        match self {
+            Object::List(o) => o.hit_counting(r, ray_t, visits),
+            Object::Node(o) => o.hit_counting(r, ray_t, visits),
+            Object::Transform(o) => o.hit_counting(r, ray_t, visits),
+            _ => self.hit(r, ray_t),
+        }
+    }
+}
+
+impl HittableList {
+    pub fn hit_counting(
+        &self,
+        r: &Ray,
+        ray_t: &Interval,
+        visits: &mut u32,
+    ) -> Option<HitRecord<'_>> {
+        let mut rec = None;
+        let mut closest_so_far = ray_t.max;
+
+        for object in self.objects.iter() {
+            let interval = Interval {
+                min: ray_t.min,
+                max: closest_so_far,
+            };
+            if let Some(temp_rec) = object.hit_counting(r, &interval, visits) {
+                closest_so_far = temp_rec.t;
+                rec = Some(temp_rec);
+            }
+        }
+
+        rec
+    }
+}
+
+impl BvhNode {
+    pub fn hit_counting(
+        &self,
+        r: &Ray,
+        ray_t: &Interval,
+        visits: &mut u32,
+    ) -> Option<HitRecord<'_>> {
+        *visits += 1;
+
+        if !self.bbox.hit(r, ray_t) {
+            return None;
+        }
+
+        match self.left.hit_counting(r, ray_t, visits) {
+            Some(rec) => {
+                let interval = Interval {
+                    min: ray_t.min,
+                    max: rec.t,
+                };
+                match self.right.hit_counting(r, &interval, visits) {
+                    None => Some(rec),
+                    x => x,
+                }
+            }
+            None => self.right.hit_counting(r, ray_t, visits),
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::f64::consts::PI;
+
+use crate::{
+    onb::Onb,
+    vec3::{dot, unit_vector, Vec3},
+};
+
+// A photometric profile from an IES LM-63 file, giving how a fixture's intensity changes with
+// direction. Only type C photometry is supported, which is what nearly all architectural
+// fixtures use: vertical angles are measured from the nadir (straight down out of the fixture)
+// and horizontal angles around it.
+#[derive(Clone)]
+pub struct IesProfile {
+    vertical: Vec<f64>,   // degrees, increasing from 0 or 90
+    horizontal: Vec<f64>, // degrees, increasing from 0
+    candela: Vec<f64>, // one row of vertical.len() values per horizontal angle, scaled to a peak of 1
+    peak_candela: f64,
+    lumens: Option<f64>, // total rated lumens, None for absolute photometry
+    solid_angle: f64,
+}
+
+impl IesProfile {
+    pub fn from_file(filename: &str) -> Result<IesProfile, String> {
+        let text = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Could not open IES file {}: {}", filename, e))?;
+
+        IesProfile::parse(&text).map_err(|e| format!("Could not read IES file {}: {}", filename, e))
+    }
+
+    pub fn parse(text: &str) -> Result<IesProfile, String> {
+        // Everything up to the TILT line is free form keywords
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => {
+                    break line.trim_start()["TILT=".len()..].trim().to_string()
+                }
+                Some(_) => continue,
+                None => return Err("no TILT line".to_string()),
+            }
+        };
+
+        let numbers = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>().map_err(|_| format!("bad number {:?}", s)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        let mut numbers = numbers.into_iter();
+        let mut next = || {
+            numbers
+                .next()
+                .ok_or_else(|| "file ends too early".to_string())
+        };
+
+        // Lamp tilt factors only matter for lamps mounted at an angle, skip them
+        if tilt == "INCLUDE" {
+            next()?; // lamp to luminaire geometry
+            let count = next()? as usize;
+            for _ in 0..2 * count {
+                next()?;
+            }
+        }
+
+        let lamps = next()?;
+        let lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()?;
+        for _ in 0..7 {
+            next()?; // units, luminous opening dimensions, ballast factors and input watts
+        }
+
+        if photometric_type != 1. {
+            return Err("only type C photometry is supported".to_string());
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err("no angles".to_string());
+        }
+
+        let vertical = (0..vertical_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+        let horizontal = (0..horizontal_count)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut candela = (0..vertical_count * horizontal_count)
+            .map(|_| next().map(|c| c * multiplier))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let peak_candela = candela.iter().copied().fold(0., f64::max);
+        if peak_candela > 0. {
+            candela.iter_mut().for_each(|c| *c /= peak_candela);
+        }
+
+        let mut profile = IesProfile {
+            vertical,
+            horizontal,
+            candela,
+            peak_candela,
+            lumens: (lumens_per_lamp > 0.).then_some(lamps * lumens_per_lamp),
+            solid_angle: 0.,
+        };
+        profile.solid_angle = profile.integrate();
+        Ok(profile)
+    }
+
+    pub fn peak_candela(&self) -> f64 {
+        self.peak_candela
+    }
+
+    pub fn lumens(&self) -> Option<f64> {
+        self.lumens
+    }
+
+    // Relative intensity from 0 to 1 towards direction, for a fixture pointing along nadir
+    pub fn value(&self, direction: &Vec3, nadir: &Vec3) -> f64 {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(nadir);
+
+        let d = unit_vector(direction);
+        let gamma = dot(&d, &uvw.w()).clamp(-1., 1.).acos().to_degrees();
+        let phi = dot(&d, &uvw.v()).atan2(dot(&d, &uvw.u())).to_degrees();
+
+        self.lookup(gamma, self.fold_horizontal(phi.rem_euclid(360.)))
+    }
+
+    // Profiles only store the part of the circle their symmetry doesn't repeat
+    fn fold_horizontal(&self, phi: f64) -> f64 {
+        let last = self.horizontal[self.horizontal.len() - 1];
+
+        if last == 0. {
+            0.
+        } else if last == 90. {
+            let phi = if phi > 180. { 360. - phi } else { phi };
+            if phi > 90. {
+                180. - phi
+            } else {
+                phi
+            }
+        } else if last == 180. {
+            if phi > 180. {
+                360. - phi
+            } else {
+                phi
+            }
+        } else {
+            phi
+        }
+    }
+
+    fn lookup(&self, gamma: f64, phi: f64) -> f64 {
+        let nv = self.vertical.len();
+        let row = |h: usize| &self.candela[h * nv..(h + 1) * nv];
+
+        // Nothing is emitted outside the measured vertical range
+        let first = self.vertical[0];
+        let last = self.vertical[nv - 1];
+        if gamma < first || gamma > last {
+            return 0.;
+        }
+
+        let (h0, h1, th) = bracket(&self.horizontal, phi);
+        let (v0, v1, tv) = bracket(&self.vertical, gamma);
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let c0 = lerp(row(h0)[v0], row(h0)[v1], tv);
+        let c1 = lerp(row(h1)[v0], row(h1)[v1], tv);
+        lerp(c0, c1, th)
+    }
+
+    // Total power of the profile's distribution as a multiple of an isotropic source of intensity 1
+    pub fn solid_angle(&self) -> f64 {
+        self.solid_angle
+    }
+
+    // solid_angle, integrated numerically over the sphere
+    fn integrate(&self) -> f64 {
+        let steps = 90;
+        let nadir = Vec3::new(0., 0., 1.);
+
+        let mut total = 0.;
+        for i in 0..steps {
+            let theta = (i as f64 + 0.5) / steps as f64 * PI;
+            for j in 0..2 * steps {
+                let phi = (j as f64 + 0.5) / (2 * steps) as f64 * 2. * PI;
+                let d = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                total += self.value(&d, &nadir) * theta.sin();
+            }
+        }
+
+        total * (PI / steps as f64) * (PI / steps as f64)
+    }
+}
+
+// Indices of the two angles around x and how far x is between them, clamped at the ends
+fn bracket(angles: &[f64], x: f64) -> (usize, usize, f64) {
+    let n = angles.len();
+    if n == 1 || x <= angles[0] {
+        return (0, 0, 0.);
+    }
+    if x >= angles[n - 1] {
+        return (n - 1, n - 1, 0.);
+    }
+
+    let i = angles.partition_point(|a| *a <= x) - 1;
+    let span = angles[i + 1] - angles[i];
+    let t = if span > 0. {
+        (x - angles[i]) / span
+    } else {
+        0.
+    };
+    (i, i + 1, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lamp and photometry lines for a type C profile with vertical angles 0 and 90
+    fn header(horizontal_count: usize) -> String {
+        format!("1 1000 2 2 {} 1 2 0 0 0\n1 1 10\n0 90\n", horizontal_count)
+    }
+
+    // A profile over the given horizontal angles whose intensity at the nadir grows with the
+    // horizontal angle, so folding onto the wrong angle shows up
+    fn profile(horizontal: &[f64]) -> IesProfile {
+        let angles = horizontal.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        let candela = (0..horizontal.len())
+            .map(|i| format!("{} 0", 100 + 10 * i))
+            .collect::<Vec<_>>();
+        let text = format!(
+            "IESNA:LM-63-2002\nTILT=NONE\n{}{}\n{}\n",
+            header(horizontal.len()),
+            angles.join(" "),
+            candela.join("\n")
+        );
+
+        IesProfile::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn parses_a_profile() {
+        let p = profile(&[0., 90.]);
+
+        assert_eq!(p.vertical, vec![0., 90.]);
+        assert_eq!(p.horizontal, vec![0., 90.]);
+        assert_eq!(p.peak_candela(), 220.);
+        assert_eq!(p.lumens(), Some(1000.));
+        assert_eq!(p.candela, vec![200. / 220., 0., 1., 0.]);
+    }
+
+    #[test]
+    fn skips_included_tilt_data() {
+        let text = format!(
+            "IESNA:LM-63-2002\n[TEST] tilted\nTILT=INCLUDE\n1\n3\n0 45 90\n1 0.9 0.8\n{}0\n100 50\n",
+            header(1)
+        );
+        let p = IesProfile::parse(&text).unwrap();
+
+        assert_eq!(p.vertical, vec![0., 90.]);
+        assert_eq!(p.horizontal, vec![0.]);
+        assert_eq!(p.peak_candela(), 200.);
+    }
+
+    #[test]
+    fn folds_horizontal_angles_by_symmetry() {
+        // Rotationally symmetric
+        let p = profile(&[0.]);
+        assert_eq!(p.fold_horizontal(123.), 0.);
+
+        // Symmetric in each quadrant
+        let p = profile(&[0., 45., 90.]);
+        assert_eq!(p.fold_horizontal(30.), 30.);
+        assert_eq!(p.fold_horizontal(135.), 45.);
+        assert_eq!(p.fold_horizontal(225.), 45.);
+        assert_eq!(p.fold_horizontal(300.), 60.);
+
+        // Symmetric about the 0-180 plane
+        let p = profile(&[0., 90., 180.]);
+        assert_eq!(p.fold_horizontal(100.), 100.);
+        assert_eq!(p.fold_horizontal(270.), 90.);
+
+        // No symmetry
+        let p = profile(&[0., 90., 180., 270., 360.]);
+        assert_eq!(p.fold_horizontal(300.), 300.);
+    }
+
+    #[test]
+    fn quadrant_profiles_look_the_same_mirrored() {
+        let p = profile(&[0., 45., 90.]);
+        let nadir = Vec3::new(0., -1., 0.);
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&nadir);
+
+        let (a, b, c) = (0.3, 0.2, 1.);
+        let value = p.value(&uvw.local_vec(&Vec3::new(a, b, c)), &nadir);
+        for (x, y) in [(-a, b), (a, -b), (-a, -b)] {
+            let mirrored = p.value(&uvw.local_vec(&Vec3::new(x, y, c)), &nadir);
+            assert!((value - mirrored).abs() < 1e-9);
+        }
+
+        // Straight down along the 45 degree plane is the middle row
+        let down = p.value(&uvw.local_vec(&Vec3::new(1., 1., 1e6)), &nadir);
+        assert!((down - 220. / 240.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let error = |text: &str| IesProfile::parse(text).err().unwrap();
+
+        assert_eq!(error("IESNA:LM-63-2002\n1 1000 1"), "no TILT line");
+        assert_eq!(
+            error("TILT=NONE\n1 1000 1 2 1 2 2 0 0 0\n1 1 10\n0 90\n0\n100 0\n"),
+            "only type C photometry is supported"
+        );
+        assert_eq!(
+            error(&format!("TILT=NONE\n{}0\n100", header(1))),
+            "file ends too early"
+        );
+        assert_eq!(
+            error(&format!("TILT=NONE\n{}0\n100 x\n", header(1))),
+            "bad number \"x\""
+        );
+    }
+}
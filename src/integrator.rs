@@ -0,0 +1,580 @@
+use std::sync::Arc;
+
+use crate::{
+    bdpt::Bdpt,
+    color::Color,
+    delta_light::DeltaLight,
+    hittable::{HitRecord, Hittable, HittableList},
+    interval::Interval,
+    material::{MatFn, Material, SrecData},
+    medium::PathMedium,
+    mlt::Mlt,
+    object::Object,
+    pdf::{AnyPDF, HittablePDF, MixturePDF, PDF},
+    photon_map::PhotonMapper,
+    preview::Preview,
+    ray::Ray,
+    render::{count_clamped, sample_layers, sample_pixels, Camera},
+    spectral::SpectralPathTracer,
+    utils::INF,
+};
+
+// Everything an integrator needs to know about what it is rendering
+pub struct Scene<'a> {
+    pub world: &'a HittableList,
+    pub delta_lights: &'a Vec<DeltaLight>,
+    pub lights: Arc<Object>,
+    pub cam: &'a Camera,
+    // Output layers for light groups: "default" for ungrouped lights first, then the named
+    // groups, then "background"
+    pub light_groups: Vec<String>,
+}
+
+impl Scene<'_> {
+    pub fn hit(&self, r: &Ray) -> Option<HitRecord> {
+        self.world.hit(
+            r,
+            &Interval {
+                min: 0.0001,
+                max: INF,
+            },
+        )
+    }
+
+    // Index into light_groups of the layer that light emitted by mat belongs to
+    pub fn light_group(&self, mat: &Material) -> usize {
+        self.group_index(mat.light_group())
+    }
+
+    // The same for light from a delta light
+    pub fn delta_light_group(&self, light: &DeltaLight) -> usize {
+        self.group_index(light.group())
+    }
+
+    fn group_index(&self, group: Option<&str>) -> usize {
+        group
+            .and_then(|group| self.light_groups.iter().position(|g| g == group))
+            .unwrap_or(0)
+    }
+
+    pub fn background_group(&self) -> usize {
+        self.light_groups.len() - 1
+    }
+
+    // False when lights is an empty list, which has no directions to sample
+    pub fn has_lights(&self) -> bool {
+        match &*self.lights {
+            Object::List(list) => !list.objects.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Integrator {
+    Path(PathTracer),
+    Direct(DirectLighting),
+    Bdpt(Bdpt),
+    Photon(PhotonMapper),
+    Spectral(SpectralPathTracer),
+    Mlt(Mlt),
+    Preview(Preview),
+}
+
+impl Integrate for Integrator {
+    fn prepare(&self, scene: &Scene) {
+        match self {
+            Integrator::Path(i) => i.prepare(scene),
+            Integrator::Direct(i) => i.prepare(scene),
+            Integrator::Bdpt(i) => i.prepare(scene),
+            Integrator::Photon(i) => i.prepare(scene),
+            Integrator::Spectral(i) => i.prepare(scene),
+            Integrator::Mlt(i) => i.prepare(scene),
+            Integrator::Preview(i) => i.prepare(scene),
+        }
+    }
+
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        match self {
+            Integrator::Path(i) => i.ray_color(r, scene),
+            Integrator::Direct(i) => i.ray_color(r, scene),
+            Integrator::Bdpt(i) => i.ray_color(r, scene),
+            Integrator::Photon(i) => i.ray_color(r, scene),
+            Integrator::Spectral(i) => i.ray_color(r, scene),
+            Integrator::Mlt(i) => i.ray_color(r, scene),
+            Integrator::Preview(i) => i.ray_color(r, scene),
+        }
+    }
+
+    fn render(&self, scene: &Scene, pixels: &mut [Color]) {
+        match self {
+            Integrator::Path(i) => i.render(scene, pixels),
+            Integrator::Direct(i) => i.render(scene, pixels),
+            Integrator::Bdpt(i) => i.render(scene, pixels),
+            Integrator::Photon(i) => i.render(scene, pixels),
+            Integrator::Spectral(i) => i.render(scene, pixels),
+            Integrator::Mlt(i) => i.render(scene, pixels),
+            Integrator::Preview(i) => i.render(scene, pixels),
+        }
+    }
+
+    fn render_groups(&self, scene: &Scene, layers: &mut [Vec<Color>]) {
+        match self {
+            Integrator::Path(i) => i.render_groups(scene, layers),
+            Integrator::Direct(i) => i.render_groups(scene, layers),
+            Integrator::Bdpt(i) => i.render_groups(scene, layers),
+            Integrator::Photon(i) => i.render_groups(scene, layers),
+            Integrator::Spectral(i) => i.render_groups(scene, layers),
+            Integrator::Mlt(i) => i.render_groups(scene, layers),
+            Integrator::Preview(i) => i.render_groups(scene, layers),
+        }
+    }
+}
+
+pub trait Integrate {
+    // Called once before rendering, to check the scene or build anything shared by every sample
+    fn prepare(&self, _scene: &Scene) {}
+
+    // Radiance arriving at the camera along r
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color;
+
+    // Accumulates samples_per_pixel samples into every pixel
+    fn render(&self, scene: &Scene, pixels: &mut [Color])
+    where
+        Self: Sync,
+    {
+        sample_pixels(scene.cam, pixels, |r| self.ray_color(r, scene));
+    }
+
+    // render split by the light group of the emitter the light came from, accumulating into one
+    // layer per scene.light_groups. Integrators that can't tell the groups apart put all of it in
+    // the default layer.
+    fn render_groups(&self, scene: &Scene, layers: &mut [Vec<Color>])
+    where
+        Self: Sync,
+    {
+        eprintln!("This integrator can't split light by group, it all goes to the default layer");
+        self.render(scene, &mut layers[0]);
+    }
+}
+
+// Samples a scattered direction from the mix of the material and light pdfs, returning the new
+// ray and its weight, the BRDF times cosine over pdf_value. This replaces the attenuation of the
+// scatter record, which can't hold BRDFs whose color changes with direction. medium is what the
+// path is in at rec.
+pub fn sample_scatter(
+    r: &Ray,
+    rec: &HitRecord,
+    pdf_ptr: Box<AnyPDF>,
+    scene: &Scene,
+    medium: &PathMedium,
+) -> (Ray, Color) {
+    let lights = scene
+        .has_lights()
+        .then(|| HittablePDF::new(scene.lights.clone(), rec.p));
+    let sun = scene.cam.background.sun().map(|sun| sun.pdf());
+
+    let light_pdf = match (lights, sun) {
+        (Some(lights), Some(sun)) => Some(AnyPDF::Mixed(MixturePDF::new(
+            Box::new(lights),
+            Box::new(sun),
+        ))),
+        (lights, sun) => lights.or(sun),
+    };
+
+    let p = match light_pdf {
+        Some(light_pdf) => AnyPDF::Mixed(MixturePDF::new(Box::new(light_pdf), pdf_ptr)),
+        None => *pdf_ptr,
+    };
+
+    let scattered = Ray::new_timed(rec.p, p.generate(), r.time());
+
+    let pdf_val = p.value(&scattered.direction());
+    if pdf_val <= 0. {
+        return (scattered, Color::new_zero());
+    }
+
+    let weight = medium.eval(r, rec, &scattered) / pdf_val;
+    (scattered, weight)
+}
+
+// Light reaching rec straight from the delta lights and scattered back along r. Paths can never
+// hit these lights, so every diffuse vertex sends a shadow ray to each of them instead. medium is
+// what the path is in at rec, which the shadow rays travel through.
+pub fn sample_delta_lights(r: &Ray, rec: &HitRecord, scene: &Scene, medium: &PathMedium) -> Color {
+    let mut direct = Color::new_zero();
+    sample_delta_lights_groups(r, rec, scene, medium, |_, c| direct = direct + c);
+    direct
+}
+
+// sample_delta_lights, handing the light of every delta light to add along with its light group
+pub fn sample_delta_lights_groups<F>(
+    r: &Ray,
+    rec: &HitRecord,
+    scene: &Scene,
+    medium: &PathMedium,
+    mut add: F,
+) where
+    F: FnMut(usize, Color),
+{
+    for light in scene.delta_lights.iter() {
+        let sample = match light.sample(&rec.p) {
+            Some(sample) => sample,
+            None => continue,
+        };
+
+        let shadow_ray = Ray::new_timed(rec.p, sample.direction, r.time());
+        let blocked = scene.world.hit(
+            &shadow_ray,
+            &Interval {
+                min: 0.0001,
+                max: sample.distance - 0.0001,
+            },
+        );
+
+        if blocked.is_none() {
+            let transmittance = medium.shadow_transmittance(sample.distance);
+            add(
+                scene.delta_light_group(light),
+                medium.eval(r, rec, &shadow_ray) * sample.radiance * transmittance,
+            );
+        }
+    }
+}
+
+// Scales down what a single path adds after depth bounces so no channel goes over
+// cam.clamp_indirect. Light reaching the first surface the camera sees is never clamped.
+pub fn clamp_indirect(cam: &Camera, contribution: Color, depth: i32) -> Color {
+    match cam.clamp_indirect {
+        Some(max) if depth >= 2 => {
+            let brightest = contribution.x().max(contribution.y()).max(contribution.z());
+            if brightest > max {
+                count_clamped();
+                contribution * (max / brightest)
+            } else {
+                contribution
+            }
+        }
+        _ => contribution,
+    }
+}
+
+// Unidirectional path tracing with light sampling
+#[derive(Clone)]
+pub struct PathTracer;
+
+impl PathTracer {
+    pub fn new() -> Integrator {
+        Integrator::Path(PathTracer)
+    }
+}
+
+impl PathTracer {
+    // Follows one path from the camera, handing every bit of light it finds to add
+    // along with the light group it came from
+    fn trace<F>(&self, r: &Ray, scene: &Scene, mut add: F)
+    where
+        F: FnMut(usize, Color),
+    {
+        let mut r = r.clone();
+        let mut throughput = Color::new(1., 1., 1.);
+        let mut medium = PathMedium::default();
+
+        // check if we hit bounce limit
+        for depth in 0..scene.cam.max_depth {
+            let (hit, transmittance) = medium.hit(scene, &r);
+            throughput = throughput * transmittance;
+
+            let rec = match hit {
+                Some(rec) => rec,
+                None => {
+                    // This sets the skybox + ambient light
+                    let background = throughput * scene.cam.background.value(&r);
+                    add(
+                        scene.background_group(),
+                        clamp_indirect(scene.cam, background, depth),
+                    );
+                    break;
+                }
+            };
+
+            let color_from_emission = rec.mat.emitted(&r, &rec, rec.u, rec.v, &rec.p);
+            add(
+                scene.light_group(rec.mat),
+                clamp_indirect(scene.cam, throughput * color_from_emission, depth),
+            );
+
+            let srec = match medium.scatter(&r, &rec) {
+                Some(srec) => srec,
+                None => break,
+            };
+
+            match srec.data {
+                SrecData::SkipRay(skip_ray) => {
+                    throughput = throughput * srec.attenuation;
+                    medium.cross(&rec, &skip_ray);
+                    r = skip_ray;
+                }
+                SrecData::PdfPtr(pdf_ptr) => {
+                    sample_delta_lights_groups(&r, &rec, scene, &medium, |group, c| {
+                        add(group, clamp_indirect(scene.cam, throughput * c, depth + 1))
+                    });
+
+                    let (scattered, weight) = sample_scatter(&r, &rec, pdf_ptr, scene, &medium);
+                    throughput = throughput * weight;
+                    medium.cross(&rec, &scattered);
+                    r = scattered;
+                }
+            }
+        }
+    }
+}
+
+impl Integrate for PathTracer {
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        let mut radiance = Color::new_zero();
+        self.trace(r, scene, |_, c| radiance = radiance + c);
+        radiance
+    }
+
+    fn render_groups(&self, scene: &Scene, layers: &mut [Vec<Color>]) {
+        sample_layers(scene.cam, layers, |r, groups| {
+            self.trace(r, scene, |group, c| groups[group] = groups[group] + c)
+        });
+    }
+}
+
+// Only counts light reaching a diffuse surface directly from an emitter or the background,
+// following mirror and glass bounces on the way
+#[derive(Clone)]
+pub struct DirectLighting;
+
+impl DirectLighting {
+    pub fn new() -> Integrator {
+        Integrator::Direct(DirectLighting)
+    }
+}
+
+impl DirectLighting {
+    // Follows one path from the camera, handing the light it finds to add along with the light
+    // group it came from
+    fn trace<F>(&self, r: &Ray, scene: &Scene, mut add: F)
+    where
+        F: FnMut(usize, Color),
+    {
+        let mut r = r.clone();
+        let mut throughput = Color::new(1., 1., 1.);
+        let mut medium = PathMedium::default();
+
+        for _ in 0..scene.cam.max_depth {
+            let (hit, transmittance) = medium.hit(scene, &r);
+            throughput = throughput * transmittance;
+
+            let rec = match hit {
+                Some(rec) => rec,
+                None => {
+                    add(
+                        scene.background_group(),
+                        throughput * scene.cam.background.value(&r),
+                    );
+                    return;
+                }
+            };
+
+            add(
+                scene.light_group(rec.mat),
+                throughput * rec.mat.emitted(&r, &rec, rec.u, rec.v, &rec.p),
+            );
+
+            let srec = match medium.scatter(&r, &rec) {
+                Some(srec) => srec,
+                None => break,
+            };
+
+            match srec.data {
+                SrecData::SkipRay(skip_ray) => {
+                    throughput = throughput * srec.attenuation;
+                    medium.cross(&rec, &skip_ray);
+                    r = skip_ray;
+                }
+                SrecData::PdfPtr(pdf_ptr) => {
+                    sample_delta_lights_groups(&r, &rec, scene, &medium, |group, c| {
+                        add(group, throughput * c)
+                    });
+
+                    let (scattered, weight) = sample_scatter(&r, &rec, pdf_ptr, scene, &medium);
+                    medium.cross(&rec, &scattered);
+
+                    let (hit, transmittance) = medium.hit(scene, &scattered);
+                    let throughput = throughput * weight * transmittance;
+
+                    match hit {
+                        Some(light_rec) => add(
+                            scene.light_group(light_rec.mat),
+                            throughput
+                                * light_rec.mat.emitted(
+                                    &scattered,
+                                    &light_rec,
+                                    light_rec.u,
+                                    light_rec.v,
+                                    &light_rec.p,
+                                ),
+                        ),
+                        None => add(
+                            scene.background_group(),
+                            throughput * scene.cam.background.value(&scattered),
+                        ),
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Integrate for DirectLighting {
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        let mut radiance = Color::new_zero();
+        self.trace(r, scene, |_, c| radiance = radiance + c);
+        radiance
+    }
+
+    fn render_groups(&self, scene: &Scene, layers: &mut [Vec<Color>]) {
+        sample_layers(scene.cam, layers, |r, groups| {
+            self.trace(r, scene, |group, c| groups[group] = groups[group] + c)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        material::{DiffuseLight, Lambertian},
+        object::{Quad, Sphere},
+        render::init_pixels,
+        sampler::{set_sample_stream, SampleStream},
+        vec3::{Point3, Vec3},
+    };
+
+    // Replays the same random numbers for every render that starts from the same seed
+    struct Seeded(StdRng);
+
+    impl SampleStream for Seeded {
+        fn next_sample(&mut self) -> f64 {
+            self.0.gen()
+        }
+    }
+
+    fn seed() {
+        set_sample_stream(Some(Rc::new(RefCell::new(Seeded(StdRng::seed_from_u64(7))))));
+    }
+
+    // A floor lit by a grouped area light, an ungrouped sphere light, a grouped point light and the sky
+    fn lit_floor() -> (HittableList, Vec<DeltaLight>) {
+        let mut world = HittableList::new();
+        world.add(Quad::new(
+            Point3::new(-5., 0., -5.),
+            Vec3::new(10., 0., 0.),
+            Vec3::new(0., 0., 10.),
+            Lambertian::new(Color::new(0.7, 0.6, 0.5)),
+        ));
+        world.add(Quad::new(
+            Point3::new(-1., 3., -1.),
+            Vec3::new(2., 0., 0.),
+            Vec3::new(0., 0., 2.),
+            DiffuseLight::new_in_group(Color::new(4., 4., 4.), "key"),
+        ));
+        world.add(Sphere::new(
+            Point3::new(2., 1., 0.),
+            0.5,
+            DiffuseLight::new(Color::new(2., 1., 0.5)),
+        ));
+
+        let fill = DeltaLight::from(crate::delta_light::PointLight::new(
+            Point3::new(-2., 2., 1.),
+            Color::new(3., 3., 3.),
+        ))
+        .in_group("fill");
+
+        (world, vec![fill])
+    }
+
+    #[test]
+    fn light_group_layers_sum_to_beauty() {
+        let (world, delta_lights) = lit_floor();
+        let mut cam = Camera::new(
+            1.,
+            6,
+            4,
+            5,
+            60.,
+            Point3::new(0., 2., 6.),
+            Point3::new(0., 0., 0.),
+            Vec3::new(0., 1., 0.),
+            0.,
+            0.,
+            Color::new(0.2, 0.3, 0.4),
+        );
+        let light_groups = ["default", "key", "fill", "background"].map(String::from).to_vec();
+        let pixels = init_pixels(&cam).len();
+
+        // One thread draws every sample in the same order, so both renders see the same numbers
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .expect("should work");
+
+        // Whether the integrator really splits the light, or leaves it all in the default layer
+        for (integrator, splits) in [
+            (PathTracer::new(), true),
+            (DirectLighting::new(), true),
+            (SpectralPathTracer::new(), true),
+            (Bdpt::new(), false),
+        ] {
+            cam.integrator = integrator;
+            let scene = Scene {
+                world: &world,
+                delta_lights: &delta_lights,
+                lights: Arc::new(Object::List(Arc::new(world.collect_lights()))),
+                cam: &cam,
+                light_groups: light_groups.clone(),
+            };
+
+            let (beauty, layers) = pool.install(|| {
+                cam.integrator.prepare(&scene);
+
+                seed();
+                let mut beauty = vec![Color::new_zero(); pixels];
+                cam.integrator.render(&scene, &mut beauty);
+
+                seed();
+                let mut layers = vec![vec![Color::new_zero(); pixels]; light_groups.len()];
+                cam.integrator.render_groups(&scene, &mut layers);
+
+                set_sample_stream(None);
+                (beauty, layers)
+            });
+
+            assert!(beauty.iter().any(|c| c.length_squared() > 0.));
+            for layer in layers.iter().skip(1) {
+                assert_eq!(layer.iter().any(|c| c.length_squared() > 0.), splits);
+            }
+            for (i, pixel) in beauty.iter().enumerate() {
+                let sum = layers.iter().fold(Color::new_zero(), |acc, l| acc + l[i]);
+                assert!(
+                    (sum - *pixel).length() <= 1e-9 * (1. + pixel.length()),
+                    "pixel {} has layers summing to {:?} instead of {:?}",
+                    i,
+                    sum,
+                    pixel
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::sync::Arc;
+
+use crate::{
+    distribution::Distribution1D,
+    hittable::{HitRecord, Hittable, HittableList},
+    interval::Interval,
+    object::{Aabb, Object},
+    ray::Ray,
+    utils::{random_double, INF},
+    vec3::{Point3, Vec3},
+};
+
+// Above this many lights a light tree is used instead of picking by power alone
+const LIGHT_TREE_THRESHOLD: usize = 16;
+
+// The object to sample lights from: nothing for no lights, a power weighted list for a few,
+// and a light tree for many
+pub fn light_sampler(lights: HittableList) -> Object {
+    if lights.objects.is_empty() {
+        Object::List(Arc::new(lights))
+    } else if lights.objects.len() <= LIGHT_TREE_THRESHOLD {
+        PowerLights::new(lights)
+    } else {
+        LightTree::new(lights)
+    }
+}
+
+// Picks lights in proportion to the power they emit
+pub struct PowerLights {
+    lights: Vec<Object>,
+    distribution: Distribution1D,
+    bbox: Aabb,
+}
+
+impl PowerLights {
+    pub fn new(lights: HittableList) -> Object {
+        let distribution = Distribution1D::new(lights.objects.iter().map(|l| l.power()).collect());
+        let bbox = *lights
+            .bounding_box()
+            .expect("Lists always have a bounding box");
+
+        Object::PowerLights(Arc::new(PowerLights {
+            lights: lights.objects,
+            distribution,
+            bbox,
+        }))
+    }
+
+    fn pick(&self) -> (&Object, f64) {
+        let (i, pmf) = self.distribution.sample_discrete(random_double());
+        (&self.lights[i], pmf)
+    }
+}
+
+impl Hittable for PowerLights {
+    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        closest_hit(self.lights.iter(), r, ray_t)
+    }
+
+    fn bounding_box(&self) -> Option<&Aabb> {
+        Some(&self.bbox)
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.lights
+            .iter()
+            .enumerate()
+            .map(|(i, l)| self.distribution.discrete_pmf(i) * l.pdf_value(origin, direction))
+            .sum()
+    }
+
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.pick().0.random(origin)
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        let (light, pmf) = self.pick();
+        let (rec, pdf) = light.sample_surface(time)?;
+        Some((rec, pdf * pmf))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        self.lights
+            .iter()
+            .enumerate()
+            .map(|(i, l)| self.distribution.discrete_pmf(i) * l.surface_pdf(origin, p))
+            .sum()
+    }
+}
+
+enum LightNode {
+    // Objects are much bigger than the interior payload, so leaves keep theirs on the heap
+    Leaf {
+        light: Box<Object>,
+        power: f64,
+        bbox: Aabb,
+    },
+    Interior {
+        left: Box<LightNode>,
+        right: Box<LightNode>,
+        power: f64,
+        bbox: Aabb,
+    },
+}
+
+impl LightNode {
+    fn build(mut lights: Vec<(Object, f64, Aabb)>) -> LightNode {
+        if lights.len() == 1 {
+            let (light, power, bbox) = lights.pop().expect("One light left");
+            return LightNode::Leaf {
+                light: Box::new(light),
+                power,
+                bbox,
+            };
+        }
+
+        let bbox = lights
+            .iter()
+            .fold(Aabb::empty(), |acc, (_, _, b)| Aabb::from_boxes(&acc, b));
+
+        // Split at the median centroid along the longest axis
+        let extent = bbox.diagonal();
+        let axis = (0..3)
+            .max_by(|a, b| extent.dim(*a).total_cmp(&extent.dim(*b)))
+            .unwrap_or(0);
+        lights.sort_by(|a, b| {
+            a.2.centroid()
+                .dim(axis)
+                .total_cmp(&b.2.centroid().dim(axis))
+        });
+
+        let right = lights.split_off(lights.len() / 2);
+        let left = LightNode::build(lights);
+        let right = LightNode::build(right);
+
+        LightNode::Interior {
+            power: left.power() + right.power(),
+            bbox,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn power(&self) -> f64 {
+        match self {
+            LightNode::Leaf { power, .. } | LightNode::Interior { power, .. } => *power,
+        }
+    }
+
+    fn bbox(&self) -> &Aabb {
+        match self {
+            LightNode::Leaf { bbox, .. } | LightNode::Interior { bbox, .. } => bbox,
+        }
+    }
+
+    // Rough contribution of everything below this node to the point p: its power over the squared
+    // distance, which is not allowed to shrink below the node's own size
+    fn importance(&self, p: &Point3) -> f64 {
+        let bbox = self.bbox();
+        let distance_squared = (bbox.centroid() - *p).length_squared();
+        let half_diagonal_squared = 0.25 * bbox.diagonal().length_squared();
+
+        self.power() / distance_squared.max(half_diagonal_squared).max(1e-8)
+    }
+
+    // Probabilities of descending into the left and right child from p
+    fn child_probabilities(left: &LightNode, right: &LightNode, p: &Point3) -> (f64, f64) {
+        let l = left.importance(p);
+        let r = right.importance(p);
+
+        if l + r > 0. {
+            (l / (l + r), r / (l + r))
+        } else {
+            (0.5, 0.5)
+        }
+    }
+
+    fn pick(&self, p: &Point3) -> (&Object, f64) {
+        let mut node = self;
+        let mut pmf = 1.;
+
+        loop {
+            match node {
+                LightNode::Leaf { light, .. } => return (light, pmf),
+                LightNode::Interior { left, right, .. } => {
+                    let (p_left, p_right) = LightNode::child_probabilities(left, right, p);
+                    if random_double() < p_left {
+                        node = left;
+                        pmf *= p_left;
+                    } else {
+                        node = right;
+                        pmf *= p_right;
+                    }
+                }
+            }
+        }
+    }
+
+    // Only lights whose boxes the direction passes through can contribute
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        match self {
+            LightNode::Leaf { light, .. } => light.pdf_value(origin, direction),
+            LightNode::Interior {
+                left, right, bbox, ..
+            } => {
+                let r = Ray::new(*origin, *direction);
+                if !bbox.hit(
+                    &r,
+                    &Interval {
+                        min: 0.001,
+                        max: INF,
+                    },
+                ) {
+                    return 0.;
+                }
+
+                let (p_left, p_right) = LightNode::child_probabilities(left, right, origin);
+                p_left * left.pdf_value(origin, direction)
+                    + p_right * right.pdf_value(origin, direction)
+            }
+        }
+    }
+
+    // Only lights whose boxes hold p can have picked it. The children are weighed from
+    // shading_point, which has to match the point sample_surface picked from.
+    fn surface_pdf(&self, origin: &Point3, p: &Point3, shading_point: &Point3) -> f64 {
+        match self {
+            LightNode::Leaf { light, .. } => light.surface_pdf(origin, p),
+            LightNode::Interior {
+                left, right, bbox, ..
+            } => {
+                let delta = 0.0001;
+                let padded = Aabb::new(
+                    bbox.x.expand(delta),
+                    bbox.y.expand(delta),
+                    bbox.z.expand(delta),
+                );
+                if !padded.contains(p) {
+                    return 0.;
+                }
+
+                let (p_left, p_right) = LightNode::child_probabilities(left, right, shading_point);
+                p_left * left.surface_pdf(origin, p, shading_point)
+                    + p_right * right.surface_pdf(origin, p, shading_point)
+            }
+        }
+    }
+}
+
+// A bounding volume hierarchy over the lights. Sampling walks down from the root, choosing each
+// child by how much light it could send to the shading point, so thousands of lights cost about
+// as much to sample as a handful.
+pub struct LightTree {
+    root: LightNode,
+    lights: Vec<Object>,
+}
+
+impl LightTree {
+    pub fn new(lights: HittableList) -> Object {
+        let leaves = lights
+            .objects
+            .iter()
+            .map(|l| {
+                let bbox = *l.bounding_box().expect("Lights need a bounding box");
+                (l.clone(), l.power(), bbox)
+            })
+            .collect();
+
+        Object::LightTree(Arc::new(LightTree {
+            root: LightNode::build(leaves),
+            lights: lights.objects,
+        }))
+    }
+}
+
+impl Hittable for LightTree {
+    fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
+        closest_hit(self.lights.iter(), r, ray_t)
+    }
+
+    fn bounding_box(&self) -> Option<&Aabb> {
+        Some(self.root.bbox())
+    }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.root.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.root.pick(origin).0.random(origin)
+    }
+
+    // Light paths start without a shading point, so they pick by power alone from the scene center
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        let center = self.root.bbox().centroid();
+        let (light, pmf) = self.root.pick(&center);
+        let (rec, pdf) = light.sample_surface(time)?;
+        Some((rec, pdf * pmf))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        self.root
+            .surface_pdf(origin, p, &self.root.bbox().centroid())
+    }
+}
+
+fn closest_hit<'a>(
+    objects: impl Iterator<Item = &'a Object>,
+    r: &Ray,
+    ray_t: &Interval,
+) -> Option<HitRecord<'a>> {
+    let mut rec = None;
+    let mut closest_so_far = ray_t.max;
+
+    for object in objects {
+        if let Some(temp_rec) = object.hit(
+            r,
+            &Interval {
+                min: ray_t.min,
+                max: closest_so_far,
+            },
+        ) {
+            closest_so_far = temp_rec.t;
+            rec = Some(temp_rec);
+        }
+    }
+
+    rec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{color::Color, material::DiffuseLight, object::Sphere};
+
+    // A grid of small lights of different brightness that don't hide each other from the origin
+    fn grid() -> HittableList {
+        let mut lights = HittableList::new();
+        for i in 0..5 {
+            for j in 0..4 {
+                let center = Point3::new(i as f64 - 2., j as f64 - 1.5, -5.);
+                let brightness = 1. + ((i * 4 + j) % 7) as f64;
+                lights.add(Sphere::new(
+                    center,
+                    0.2,
+                    DiffuseLight::new(Color::new(brightness, brightness, brightness)),
+                ));
+            }
+        }
+        lights
+    }
+
+    #[test]
+    fn light_tree_pdf_matches_selection_frequency() {
+        let lights = grid();
+        let tree = LightTree::new(grid());
+        let origin = Point3::new(0.3, -0.2, 0.);
+
+        let n = 200_000;
+        let mut counts = vec![0; lights.objects.len()];
+        let mut selection = vec![0.; lights.objects.len()];
+
+        for _ in 0..n {
+            let direction = tree.random(&origin);
+            let r = Ray::new(origin, direction);
+            let interval = Interval {
+                min: 0.001,
+                max: INF,
+            };
+            let i = lights
+                .objects
+                .iter()
+                .position(|l| l.hit(&r, &interval).is_some())
+                .expect("Sampled directions point at a light");
+
+            counts[i] += 1;
+            // Inside one light's cone the tree's pdf is its chance of picking that light
+            // times the light's own pdf
+            selection[i] = tree.pdf_value(&origin, &direction)
+                / lights.objects[i].pdf_value(&origin, &direction);
+        }
+
+        for (count, probability) in counts.iter().zip(selection) {
+            let frequency = *count as f64 / n as f64;
+            assert!(
+                (frequency - probability).abs() < 0.005,
+                "picked {} of the time, pdf says {}",
+                frequency,
+                probability
+            );
+        }
+    }
+
+    #[test]
+    fn light_tree_selection_sums_to_one() {
+        let tree = LightTree::new(grid());
+        let origin = Point3::new(-1., 0.5, 1.);
+
+        let total: f64 = grid()
+            .objects
+            .iter()
+            .map(|l| {
+                let center = l.bounding_box().expect("Spheres have boxes").centroid();
+                let direction = center - origin;
+                tree.pdf_value(&origin, &direction) / l.pdf_value(&origin, &direction)
+            })
+            .sum();
+        assert!((total - 1.).abs() < 1e-9);
+    }
+}
@@ -1,8 +1,10 @@
 mod color;
 mod constant_medium;
+mod heterogeneous_medium;
 mod hittable;
 mod interval;
 mod material;
+mod medium;
 mod object;
 mod perlin;
 mod ray;
@@ -11,20 +13,55 @@ mod rt_image;
 mod texture;
 mod transform;
 mod utils;
+mod sampler;
 mod vec3;
 mod onb;
 mod pdf;
+mod phase;
+mod sky;
+mod integrator;
+mod bdpt;
+mod photon_map;
+mod spectrum;
+mod spectral;
+mod mlt;
+mod preview;
+mod distribution;
+mod lights;
+mod delta_light;
+mod ies;
+mod photometry;
+mod microfacet;
+mod denoise;
 
 use std::sync::Arc;
 
 // type aliasing
 use color::Color;
 use constant_medium::ConstantMedium;
+use delta_light::{DeltaLight, DirectionalLight, PointLight, SpotLight};
+use heterogeneous_medium::{HeterogeneousMedium, VoxelGrid};
 use hittable::{HittableList};
-use material::{Dielectric, DiffuseLight, Lambertian, Metal};
-use object::{make_box, Object, Quad, Sphere, Sun};
+use ies::IesProfile;
+use bdpt::Bdpt;
+use integrator::{DirectLighting, Integrator, PathTracer};
+use mlt::Mlt;
+use material::{
+    Conductor, Dielectric, DiffuseLight, Dispersion, Lambertian, Material, Metal, Principled,
+    Subsurface,
+};
+use medium::InteriorMedium;
+use object::{make_box, Aabb, Object, Quad, Sphere, Sun};
+use perlin::Perlin;
+use photometry::LightUnit;
+use phase::PhaseFunction;
+use photon_map::PhotonMapper;
+use preview::Preview;
 use render::{init_pixels, render_par, render_par_lights, Camera};
-use texture::{CheckerTexture, ImageTexture, NoiseTexture};
+use spectral::SpectralPathTracer;
+use sky::Sky;
+use spectrum::blackbody;
+use texture::{BlackbodyTexture, CheckerTexture, ImageTexture, NoiseTexture, SolidColor};
 use transform::{RotateY, Translate};
 use utils::{random_double, random_range};
 use vec3::{random_vec3, random_vec3_range, Point3, Vec3};
@@ -45,7 +82,9 @@ fn scene_sun_spheres() {
 
     world.add(Sphere::new(Point3::new(-1., 0., -1.25), 0.5, left.clone()));
 
-    world.add(Sphere::new(Point3::new(-1., 0., -1.25), -0.4, left));
+    // An air bubble inside the glass, which wins where they overlap
+    let bubble = Dielectric::new_clear(1.).with_priority(1);
+    world.add(Sphere::new(Point3::new(-1., 0., -1.25), 0.4, bubble));
 
     world.add(Sphere::new(Point3::new(0., -100.5, -1.), 100., ground));
 
@@ -81,11 +120,9 @@ fn scene_sun_spheres() {
         &cam,
         &world,
         &mut pixels,
-        &vec![Sun::new(
-            Vec3::new(-1., 1., 1.),
-            Color::new(1., 1., 1.) * 10.,
-            2.,
-        )],
+        &vec![
+            DirectionalLight::new(Vec3::new(-1., 1., 1.), Color::new(1., 1., 1.) * 10.).into(),
+        ],
     );
 }
 
@@ -105,13 +142,15 @@ fn scene_three_spheres() {
 
     world.add(Sphere::new(Point3::new(-1., 0., -1.), 0.5, left.clone()));
 
-    world.add(Sphere::new(Point3::new(-1., 0., -1.), -0.4, left));
+    // An air bubble inside the glass, which wins where they overlap
+    let bubble = Dielectric::new_clear(1.).with_priority(1);
+    world.add(Sphere::new(Point3::new(-1., 0., -1.), 0.4, bubble));
 
     world.add(Sphere::new(Point3::new(0., -100.5, -1.), 100., ground));
 
     world.add(Sphere::new(Point3::new(1., 0., -1.), 0.5, right));
 
-    let cam = Camera::new(
+    let mut cam = Camera::new(
         16. / 9.,
         800,
         1000,
@@ -125,6 +164,13 @@ fn scene_three_spheres() {
         Color::new(0.7, 0.8, 1.),
     );
 
+    // late afternoon sun behind the camera and to the left
+    cam.background = Sky::new(
+        Sun::from_angles(35., 210., Color::new(0.7, 0.7, 0.7), 0.53),
+        2.5,
+    )
+    .into();
+
     let mut pixels = init_pixels(&cam);
 
     let world = world.create_bvh();
@@ -371,7 +417,7 @@ fn quads() {
     render_par(&cam, &world, &mut pixels, &vec![]);
 }
 
-fn simple_light() {
+fn simple_light(light_groups: bool) {
     let mut world = HittableList::new();
 
     let pertex = Arc::new(NoiseTexture::new(4.));
@@ -386,16 +432,19 @@ fn simple_light() {
         Lambertian::from_texture(pertex),
     ));
 
-    let difflight = DiffuseLight::new(Color::new(4., 4., 4.));
     world.add(Quad::new(
         Point3::new(3., 1., -2.),
         Vec3::new(2., 0., 0.),
         Vec3::new(0., 2., 0.),
-        difflight.clone(),
+        DiffuseLight::new_in_group(Color::new(4., 4., 4.), "panel"),
+    ));
+    world.add(Sphere::new(
+        Point3::new(0., 7., 0.),
+        2.,
+        DiffuseLight::new_in_group(Color::new(4., 4., 4.), "overhead"),
     ));
-    world.add(Sphere::new(Point3::new(0., 7., 0.), 2., difflight));
 
-    let cam = Camera::new(
+    let mut cam = Camera::new(
         16. / 9.,
         400,
         400,
@@ -409,12 +458,14 @@ fn simple_light() {
         Color::new_zero(),
     );
 
+    cam.light_groups = light_groups;
+
     let mut pixels = init_pixels(&cam);
 
     render_par(&cam, &world, &mut pixels, &vec![]);
 }
 
-fn cornell_box() {
+fn cornell_box(integrator: Integrator) {
     let mut world = HittableList::new();
 
     let red = Lambertian::new(Color::new(0.65, 0.05, 0.05));
@@ -493,7 +544,7 @@ fn cornell_box() {
     lights.add(Sphere::new(Point3::new(190., 90., 190.), 90., light.clone()));
     let lights = Object::List(Arc::new(lights));
 
-    let cam = Camera::new(
+    let mut cam = Camera::new(
         1.,
         600,
         1000,
@@ -506,12 +557,13 @@ fn cornell_box() {
         0.,
         Color::new_zero(),
     );
+    cam.integrator = integrator;
 
     let mut pixels = init_pixels(&cam);
     render_par_lights(&cam, &world, &mut pixels, &vec![], Arc::new(lights));
 }
 
-fn cornell_smoke() {
+fn cornell_smoke(integrator: Integrator) {
     let mut world = HittableList::new();
 
     let red = Lambertian::new(Color::new(0.65, 0.05, 0.05));
@@ -582,7 +634,7 @@ fn cornell_smoke() {
 
     //let world = world.create_bvh();
 
-    let cam = Camera::new(
+    let mut cam = Camera::new(
         1.,
         600,
         100,
@@ -595,12 +647,1059 @@ fn cornell_smoke() {
         0.,
         Color::new_zero(),
     );
+    cam.integrator = integrator;
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn dispersion() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-5., 0., -5.),
+        Vec3::new(10., 0., 0.),
+        Vec3::new(0., 0., 10.),
+        Lambertian::new(Color::new(0.8, 0.8, 0.8)),
+    ));
+
+    // Schott SF11 dense flint glass, which splits white light much more than crown glass
+    let flint = Dielectric::new_dispersive(
+        Dispersion::Sellmeier {
+            b: [1.73759695, 0.313747346, 1.89878101],
+            c: [0.013188707, 0.0623068142, 155.23629],
+        },
+        Color::new(1., 1., 1.),
+    );
+    world.add(Sphere::new(Point3::new(-1.2, 1., 0.), 1., flint));
+
+    // Schott N-BK7 crown glass next to it, which spreads the colors much less
+    let crown = Dielectric::cauchy(1.5046, 0.0042);
+    world.add(Sphere::new(Point3::new(1.2, 1., 0.), 1., crown));
+
+    world.add(Sphere::new(
+        Point3::new(-4., 6., -2.),
+        0.5,
+        DiffuseLight::new(Color::new(40., 40., 40.)),
+    ));
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        600,
+        1000,
+        50,
+        30.,
+        Point3::new(0., 3., 8.),
+        Point3::new(0., 0.8, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.05, 0.05, 0.05),
+    );
+    cam.integrator = SpectralPathTracer::new();
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn city_lights() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-60., 0., -60.),
+        Vec3::new(120., 0., 0.),
+        Vec3::new(0., 0., 120.),
+        Lambertian::new(Color::new(0.4, 0.4, 0.4)),
+    ));
+
+    // Blocks of buildings with a street lamp at every corner
+    let concrete = Lambertian::new(Color::new(0.6, 0.6, 0.55));
+    for i in -10..10 {
+        for j in -10..10 {
+            let x = i as f64 * 6.;
+            let z = j as f64 * 6.;
+
+            let height = random_range(2., 12.);
+            world.add(make_box(
+                &Point3::new(x + 1., 0., z + 1.),
+                &Point3::new(x + 5., height, z + 5.),
+                &concrete,
+            ));
+
+            let warm = Color::new(1., 0.75, 0.4) * random_range(5., 40.);
+            world.add(Sphere::new(Point3::new(x, 1.5, z), 0.15, DiffuseLight::new(warm)));
+        }
+    }
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        400,
+        100,
+        10,
+        35.,
+        Point3::new(40., 30., 50.),
+        Point3::new(0., 0., 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.01, 0.01, 0.02),
+    );
+    cam.integrator = PathTracer::new();
+
+    let world = world.create_bvh();
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn clouds() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-50., 0., -50.),
+        Vec3::new(100., 0., 0.),
+        Vec3::new(0., 0., 100.),
+        Lambertian::new(Color::new(0.3, 0.4, 0.25)),
+    ));
+
+    // A cloud from a voxel grid: a noisy blob that thins out towards the edges of its box
+    let min = Point3::new(-5., 3., -3.);
+    let max = Point3::new(5., 7., 3.);
+    let noise = Perlin::new();
+    let grid = VoxelGrid::from_fn(80, 32, 48, Aabb::from_points(&min, &max), |p| {
+        let centered = (*p - Point3::new(0.5, 0.5, 0.5)) * 2.;
+        let falloff = 1. - centered.length();
+        (2. * falloff + 0.8 * noise.turb(&(*p * 6.)) - 0.4).max(0.) * 4.
+    });
+    let boundary = make_box(&min, &max, &Lambertian::new(Color::new_zero()));
+    world.add(HeterogeneousMedium::from_grid(
+        Arc::new(boundary),
+        grid,
+        Color::new(0.95, 0.95, 0.95),
+        PhaseFunction::HenyeyGreenstein { g: 0.7 },
+    ));
+
+    // A fog bank with its density straight from a noise texture
+    let fog = Sphere::new(Point3::new(0., 0., 0.), 4., Lambertian::new(Color::new_zero()));
+    world.add(HeterogeneousMedium::from_texture(
+        Arc::new(fog),
+        Arc::new(NoiseTexture::new(1.5)),
+        0.3,
+        0.3,
+        Color::new(0.8, 0.8, 0.85),
+        PhaseFunction::Mie { g: 0.5 },
+    ));
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        400,
+        400,
+        20,
+        40.,
+        Point3::new(0., 3., 18.),
+        Point3::new(0., 3., 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+    cam.background = Sky::new(
+        Sun::from_angles(30., 200., Color::new(0.7, 0.7, 0.7), 0.53),
+        2.5,
+    )
+    .into();
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn absorbing_glass() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-8., 0., -8.),
+        Vec3::new(16., 0., 0.),
+        Vec3::new(0., 0., 16.),
+        Lambertian::new(Color::new(0.8, 0.8, 0.8)),
+    ));
+
+    // The same green glass in three thicknesses, darker the further light travels through it
+    let green_glass = || InteriorMedium::tinted(Color::new(0.3, 0.8, 0.4), 1.);
+    for (x, radius) in [(-3., 0.4), (-1.5, 0.7), (1., 1.2)] {
+        world.add(Sphere::new(
+            Point3::new(x, radius, 0.),
+            radius,
+            Dielectric::with_medium(1.5, green_glass()),
+        ));
+    }
+
+    // Milky liquid that scatters as well as absorbs
+    let milk = InteriorMedium::new(
+        Color::new(0.02, 0.05, 0.1),
+        8.,
+        Color::new(0.95, 0.95, 0.95),
+        PhaseFunction::HenyeyGreenstein { g: 0.3 },
+    );
+    world.add(Sphere::new(
+        Point3::new(3.8, 0.8, 0.),
+        0.8,
+        Dielectric::with_medium(1.33, milk),
+    ));
+
+    world.add(Quad::new(
+        Point3::new(-2., 6., -2.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 0., 4.),
+        DiffuseLight::new(Color::new(6., 6., 6.)),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        500,
+        50,
+        30.,
+        Point3::new(0., 3., 12.),
+        Point3::new(0.4, 0.7, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.1, 0.1, 0.12),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn glass_of_water() {
+    let mut world = HittableList::new();
+
+    let checker = Arc::new(CheckerTexture::from_color(
+        0.5,
+        Color::new(0.2, 0.3, 0.1),
+        Color::new(0.9, 0.9, 0.9),
+    ));
+    world.add(Quad::new(
+        Point3::new(-8., 0., -8.),
+        Vec3::new(16., 0., 0.),
+        Vec3::new(0., 0., 16.),
+        Lambertian::from_texture(checker),
+    ));
+    world.add(Sphere::new(
+        Point3::new(0.4, 0.6, -0.3),
+        0.25,
+        Lambertian::new(Color::new(0.8, 0.2, 0.1)),
+    ));
+
+    // The water overlaps the inside of the walls and the air overlaps the top of the water, so
+    // every boundary is where the higher priority material ends
+    let glass = Material::from(Dielectric::new_clear(1.5).with_priority(1));
+    let water = Material::from(
+        Dielectric::with_medium(1.33, InteriorMedium::tinted(Color::new(0.85, 0.95, 1.), 2.))
+            .with_priority(2),
+    );
+    let air = Material::from(Dielectric::new_clear(1.).with_priority(3));
+
+    world.add(make_box(
+        &Point3::new(-1., 0., -1.),
+        &Point3::new(1., 2.5, 1.),
+        &glass,
+    ));
+    world.add(make_box(
+        &Point3::new(-0.9, 0.1, -0.9),
+        &Point3::new(0.9, 1.6, 0.9),
+        &water,
+    ));
+    world.add(make_box(
+        &Point3::new(-0.9, 1.5, -0.9),
+        &Point3::new(0.9, 2.6, 0.9),
+        &air,
+    ));
+
+    world.add(Quad::new(
+        Point3::new(-2., 7., -2.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 0., 4.),
+        DiffuseLight::new(Color::new(8., 8., 8.)),
+    ));
+
+    let mut cam = Camera::new(
+        1.,
+        600,
+        500,
+        50,
+        35.,
+        Point3::new(2., 3.5, 7.),
+        Point3::new(0., 1.1, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.1, 0.1, 0.12),
+    );
+    // Caustics through the glass and water are found by chance and leave bright speckles
+    cam.reject_outliers = Some(4.);
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn subsurface() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-8., 0., -8.),
+        Vec3::new(16., 0., 0.),
+        Vec3::new(0., 0., 16.),
+        Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+    ));
+
+    // Marble, wax and skin, with light going further into each one before it comes back out
+    let marble = Subsurface::new(Color::new(0.9, 0.9, 0.88), 0.05);
+    world.add(Sphere::new(Point3::new(-2.4, 1., 0.), 1., marble));
+
+    let wax = Subsurface::new(Color::new(0.95, 0.8, 0.5), 0.15);
+    world.add(make_box(
+        &Point3::new(-0.5, 0., -0.5),
+        &Point3::new(0.5, 2.4, 0.5),
+        &wax,
+    ));
+
+    let skin = Subsurface::new(Color::new(0.85, 0.55, 0.45), 0.25);
+    world.add(Sphere::new(Point3::new(2.4, 1., 0.), 1., skin));
+
+    // A key light from the front and a rim light behind that shows through the thin edges
+    world.add(Quad::new(
+        Point3::new(-2., 7., 2.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 0., 3.),
+        DiffuseLight::new(Color::new(6., 6., 6.)),
+    ));
+    world.add(Quad::new(
+        Point3::new(-3., 0.5, -4.),
+        Vec3::new(6., 0., 0.),
+        Vec3::new(0., 3., 0.),
+        DiffuseLight::new(Color::new(4., 4., 4.)),
+    ));
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        600,
+        500,
+        256,
+        30.,
+        Point3::new(0., 3., 12.),
+        Point3::new(0., 1., 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.05, 0.05, 0.06),
+    );
+    cam.clamp_indirect = Some(10.);
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn fire() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 0., 20.),
+        Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(-2.5, 0.8, -1.),
+        0.8,
+        Lambertian::new(Color::new(0.7, 0.7, 0.8)),
+    ));
+
+    // A fireball whose temperature and density both follow the same noise
+    let noise = Arc::new(NoiseTexture::new(1.5));
+    let fireball = Sphere::new(Point3::new(0.5, 1.5, 0.), 1.5, Lambertian::new(Color::new_zero()));
+    world.add(HeterogeneousMedium::emissive(
+        Arc::new(fireball),
+        noise.clone(),
+        4.,
+        4.,
+        Color::new(0.2, 0.2, 0.2),
+        Arc::new(BlackbodyTexture::new(noise, 2500., 6.)),
+    ));
+
+    // A candle flame glowing evenly at 1800 K
+    let flame = Sphere::new(Point3::new(3., 0.4, 1.), 0.2, Lambertian::new(Color::new_zero()));
+    world.add(ConstantMedium::emissive(
+        Arc::new(flame),
+        20.,
+        Color::new_zero(),
+        Arc::new(SolidColor::new(blackbody(1800.) * 4.)),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        500,
+        50,
+        30.,
+        Point3::new(0., 3., 12.),
+        Point3::new(0., 1.2, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn stage_lights() {
+    let mut world = HittableList::new();
+
+    let white = Lambertian::new(Color::new(0.75, 0.75, 0.75));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 0., 20.),
+        white.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -3.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 10., 0.),
+        white,
+    ));
+
+    world.add(Sphere::new(
+        Point3::new(-2., 0.8, 0.),
+        0.8,
+        Lambertian::new(Color::new(0.8, 0.3, 0.2)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(0., 1., 0.5),
+        1.,
+        Metal::new(Color::new(0.9, 0.9, 0.9), 0.1),
+    ));
+    world.add(Sphere::new(
+        Point3::new(2.2, 0.7, 0.),
+        0.7,
+        Lambertian::new(Color::new(0.2, 0.4, 0.8)),
+    ));
+
+    // Three coloured spots from above, a warm bulb in front and faint moonlight from the side
+    let lights: Vec<DeltaLight> = vec![
+        SpotLight::new(
+            Point3::new(-3., 6., 3.),
+            Point3::new(-2., 0., 0.),
+            Color::new(1., 0.3, 0.3) * 60.,
+            20.,
+            12.,
+        )
+        .into(),
+        SpotLight::new(
+            Point3::new(0., 7., 3.),
+            Point3::new(0., 0., 0.),
+            Color::new(0.3, 1., 0.3) * 60.,
+            15.,
+            5.,
+        )
+        .into(),
+        SpotLight::new(
+            Point3::new(3., 6., 3.),
+            Point3::new(2.2, 0., 0.),
+            Color::new(0.3, 0.3, 1.) * 60.,
+            25.,
+            20.,
+        )
+        .into(),
+        PointLight::new(Point3::new(0., 1.5, 4.), Color::new(1., 0.8, 0.6) * 4.).into(),
+        DeltaLight::from(DirectionalLight::new(
+            Vec3::new(1., 1., 0.5),
+            Color::new(0.3, 0.35, 0.5) * 0.3,
+        ))
+        .in_group("moonlight"),
+    ];
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        100,
+        50,
+        40.,
+        Point3::new(0., 2.5, 10.),
+        Point3::new(0., 1., 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &lights);
+}
+
+// Spotlights through three boxes of haze that scatter differently: air molecules (Rayleigh) light
+// the beam about as much from any side, while larger particles throw most of it forward towards
+// the camera
+fn hazy_beams() {
+    let mut world = HittableList::new();
+
+    let grey = Lambertian::new(Color::new(0.5, 0.5, 0.5));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 0., 20.),
+        grey.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -4.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 10., 0.),
+        grey,
+    ));
+
+    let phases = [
+        PhaseFunction::Rayleigh,
+        PhaseFunction::HenyeyGreenstein { g: 0.6 },
+        PhaseFunction::Mie { g: 0.8 },
+    ];
+    let mut lights: Vec<DeltaLight> = vec![];
+    for (i, phase) in phases.into_iter().enumerate() {
+        let x = 3.5 * (i as f64 - 1.);
+
+        let haze = make_box(
+            &Point3::new(x - 1.5, 0., -3.),
+            &Point3::new(x + 1.5, 6., 3.),
+            &Lambertian::new(Color::new_zero()),
+        );
+        world.add(ConstantMedium::with_phase(
+            haze.into(),
+            0.1,
+            Color::new(0.9, 0.9, 0.9),
+            phase,
+        ));
+
+        // Shining down and towards the camera, so forward scattering lights the beam up
+        lights.push(
+            SpotLight::new(
+                Point3::new(x, 5.5, -2.5),
+                Point3::new(x, 0., 2.),
+                Color::new(1., 0.95, 0.9) * 150.,
+                12.,
+                8.,
+            )
+            .into(),
+        );
+    }
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        200,
+        20,
+        40.,
+        Point3::new(0., 2.5, 14.),
+        Point3::new(0., 2.5, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &lights);
+}
+
+fn light_panels() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 0., 20.),
+        Lambertian::new(Color::new(0.6, 0.6, 0.6)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(-2.5, 0.7, 1.),
+        0.7,
+        Lambertian::new(Color::new(0.8, 0.8, 0.8)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(2.5, 0.7, 1.),
+        0.7,
+        Lambertian::new(Color::new(0.8, 0.8, 0.8)),
+    ));
+
+    // A screen that is mostly dark, so sampling it by brightness finds the lit squares
+    let screen = Arc::new(CheckerTexture::from_color(
+        0.4,
+        Color::new(0.02, 0.02, 0.03),
+        Color::new(6., 5., 3.),
+    ));
+    world.add(Quad::new(
+        Point3::new(-2., 0.5, -3.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 2.25, 0.),
+        DiffuseLight::from_texture(screen),
+    ));
+
+    // A panel lighting both spheres from between them
+    world.add(Quad::new(
+        Point3::new(0., 0.2, 0.),
+        Vec3::new(0., 0., 2.),
+        Vec3::new(0., 1.5, 0.),
+        DiffuseLight::new(Color::new(0.4, 0.6, 1.) * 3.).two_sided(),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        200,
+        50,
+        40.,
+        Point3::new(0., 3., 9.),
+        Point3::new(0., 1., 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+// A recessed downlight with a fairly tight beam, rotationally symmetric
+const DOWNLIGHT_IES: &str = "IESNA:LM-63-2002
+[TEST] downlight
+[MANUFAC] example
+[LUMCAT] DL-30
+TILT=NONE
+1 1200 1 10 1 1 2 0.1 0.1 0
+1 1 15
+0 10 20 30 40 50 60 70 80 90
+0
+2400 2350 2150 1650 900 350 90 25 5 0
+";
+
+// A linear fixture throwing light out to the sides in a batwing, symmetric in each quadrant
+const BATWING_IES: &str = "IESNA:LM-63-2002
+[TEST] batwing
+TILT=NONE
+1 3000 1 10 2 1 2 0.6 0.1 0
+1 1 30
+0 10 20 30 40 50 60 70 80 90
+0 90
+600 700 900 1150 1300 1100 600 250 60 0
+600 620 640 620 560 450 320 180 60 0
+";
+
+fn downlights() {
+    let mut world = HittableList::new();
+
+    let white = Lambertian::new(Color::new(0.75, 0.75, 0.75));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 0., 20.),
+        white.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -3.),
+        Vec3::new(20., 0., 0.),
+        Vec3::new(0., 10., 0.),
+        white,
+    ));
+    world.add(Sphere::new(
+        Point3::new(3., 0.7, 0.5),
+        0.7,
+        Lambertian::new(Color::new(0.8, 0.5, 0.3)),
+    ));
+
+    // A row of downlights close to the wall, washing it in scallops
+    let downlight = Arc::new(IesProfile::parse(DOWNLIGHT_IES).unwrap());
+    let mut lights = (-1..=1)
+        .map(|i| {
+            PointLight::new(Point3::new(i as f64 * 3. - 1.5, 4., -2.3), Color::new(1., 0.85, 0.7) * 25.)
+                .with_profile(downlight.clone())
+                .into()
+        })
+        .collect::<Vec<DeltaLight>>();
+
+    // An accent spot on the sphere, the same fixture tilted along its axis
+    lights.push(
+        SpotLight::new(
+            Point3::new(5., 4., 3.),
+            Point3::new(3., 0.7, 0.5),
+            Color::new(1., 0.85, 0.7) * 15.,
+            40.,
+            30.,
+        )
+        .with_profile(downlight)
+        .into(),
+    );
+
+    // A panel facing down, spreading its light sideways
+    let batwing = Arc::new(IesProfile::parse(BATWING_IES).unwrap());
+    world.add(Quad::new(
+        Point3::new(2., 4., 1.),
+        Vec3::new(2., 0., 0.),
+        Vec3::new(0., 0., 0.3),
+        DiffuseLight::new(Color::new(0.8, 0.9, 1.) * 6.).with_profile(batwing),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        100,
+        50,
+        40.,
+        Point3::new(0., 2.5, 10.),
+        Point3::new(0., 1.5, 0.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &lights);
+}
+
+// A room lit with real world lights, in metres
+fn living_room() {
+    let mut world = HittableList::new();
+
+    let wall = Lambertian::new(Color::new(0.8, 0.78, 0.72));
+    let floor = Lambertian::new(Color::new(0.45, 0.3, 0.2));
+    world.add(Quad::new(
+        Point3::new(-3., 0., -3.),
+        Vec3::new(0., 0., 6.),
+        Vec3::new(6., 0., 0.),
+        floor,
+    ));
+    world.add(Quad::new(
+        Point3::new(-3., 2.5, -3.),
+        Vec3::new(6., 0., 0.),
+        Vec3::new(0., 0., 6.),
+        wall.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(-3., 0., -3.),
+        Vec3::new(6., 0., 0.),
+        Vec3::new(0., 2.5, 0.),
+        wall.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(-3., 0., -3.),
+        Vec3::new(0., 2.5, 0.),
+        Vec3::new(0., 0., 6.),
+        wall.clone(),
+    ));
+    world.add(Quad::new(
+        Point3::new(3., 0., -3.),
+        Vec3::new(0., 0., 6.),
+        Vec3::new(0., 2.5, 0.),
+        wall,
+    ));
+
+    world.add(make_box(
+        &Point3::new(-1.2, 0., -1.5),
+        &Point3::new(0.8, 0.45, -0.7),
+        &Lambertian::new(Color::new(0.25, 0.35, 0.5)),
+    ));
+
+    // A warm 800 lumen bulb in a lamp, a cool ceiling panel and a globe giving off 1 W of light
+    world.add(Sphere::light(
+        Point3::new(-2.2, 1.5, -2.2),
+        0.06,
+        blackbody(2700.),
+        LightUnit::Lumens(800.),
+    ));
+    world.add(Quad::light(
+        Point3::new(0.5, 2.49, -0.5),
+        Vec3::new(0.6, 0., 0.),
+        Vec3::new(0., 0., 0.6),
+        blackbody(5000.),
+        LightUnit::Lumens(2000.),
+        false,
+    ));
+    world.add(Sphere::light(
+        Point3::new(2., 0.8, -2.),
+        0.15,
+        blackbody(4000.),
+        LightUnit::Watts(1.),
+    ));
+
+    // A recessed downlight from its IES file, or the built in one if there is no downlight.ies
+    let downlight = IesProfile::from_file("downlight.ies").unwrap_or_else(|e| {
+        eprintln!("{}, using the built in downlight", e);
+        IesProfile::parse(DOWNLIGHT_IES).unwrap()
+    });
+
+    let lights = vec![
+        SpotLight::photometric(
+            Point3::new(-1., 2.4, 1.),
+            Point3::new(-0.2, 0.45, -1.1),
+            blackbody(3500.),
+            LightUnit::Lumens(400.),
+            20.,
+            10.,
+        )
+        .into(),
+        PointLight::from_ies(Point3::new(1.8, 2.45, -1.), blackbody(3000.), Arc::new(downlight))
+            .into(),
+        // A bare filament bulb hanging over the table, rated by its intensity
+        PointLight::photometric(
+            Point3::new(-0.2, 1.7, -1.1),
+            blackbody(2200.),
+            LightUnit::Candela(40.),
+        )
+        .into(),
+    ];
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        600,
+        200,
+        50,
+        70.,
+        Point3::new(1.5, 1.5, 2.8),
+        Point3::new(-0.5, 0.8, -1.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new_zero(),
+    );
+    cam.exposure_value = Some(6.);
+    // 200 samples leave an indoor scene lit mostly by bounce light noisy
+    cam.denoise = true;
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &lights);
+}
+
+// Brass, gold, copper and aluminium, getting rougher from left to right
+fn rough_metals() {
+    let mut world = HittableList::new();
+
+    let checker = Arc::new(CheckerTexture::from_color(
+        0.5,
+        Color::new(0.2, 0.2, 0.2),
+        Color::new(0.8, 0.8, 0.8),
+    ));
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(0., 0., 20.),
+        Vec3::new(20., 0., 0.),
+        Lambertian::from_texture(checker),
+    ));
+
+    let metals = [
+        (Color::new(0.143, 0.374, 1.442), Color::new(3.983, 2.386, 1.603)),
+        (Color::new(0.200, 0.924, 1.102), Color::new(3.912, 2.452, 2.142)),
+        (Color::new(1.657, 0.880, 0.521), Color::new(9.224, 6.270, 4.837)),
+    ];
+    for (row, (eta, k)) in metals.into_iter().enumerate() {
+        for i in 0..5 {
+            let roughness = i as f64 / 4.;
+            world.add(Sphere::new(
+                Point3::new(i as f64 * 1.1 - 2.2, 0.5, row as f64 * -1.2),
+                0.5,
+                Conductor::from_ior(eta, k, roughness),
+            ));
+        }
+    }
+
+    // Brass in front, from its color alone with Schlick's approximation of the Fresnel term
+    for i in 0..5 {
+        world.add(Sphere::new(
+            Point3::new(i as f64 * 1.1 - 2.2, 0.5, 1.2),
+            0.5,
+            Conductor::new(Color::new(0.91, 0.78, 0.42), i as f64 / 4.),
+        ));
+    }
+
+    world.add(Quad::new(
+        Point3::new(-3., 5., -3.),
+        Vec3::new(6., 0., 0.),
+        Vec3::new(0., 0., 2.),
+        DiffuseLight::new(Color::new(4., 4., 4.)),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        200,
+        50,
+        35.,
+        Point3::new(0., 3., 7.),
+        Point3::new(0., 0.3, -1.),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.4, 0.5, 0.7),
+    );
 
     let mut pixels = init_pixels(&cam);
     render_par(&cam, &world, &mut pixels, &vec![]);
 }
 
-fn final_scene(image_width: i32, samples_per_pixel: i32, max_depth: i32) {
+// A shower door in front of some spheres, glass balls getting rougher along the front and a
+// pane with a frosted pattern etched into it
+fn frosted_glass() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(0., 0., 20.),
+        Vec3::new(20., 0., 0.),
+        Lambertian::new(Color::new(0.6, 0.6, 0.6)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(-2., 0.6, -3.),
+        0.6,
+        Lambertian::new(Color::new(0.8, 0.2, 0.2)),
+    ));
+    world.add(Sphere::new(
+        Point3::new(-0.8, 0.6, -3.5),
+        0.6,
+        Lambertian::new(Color::new(0.2, 0.7, 0.3)),
+    ));
+
+    // The shower door, a thin slab of frosted glass
+    world.add(make_box(
+        &Point3::new(-3., 0., -2.05),
+        &Point3::new(0.2, 2.5, -2.),
+        &Dielectric::rough(1.5, 0.3).into(),
+    ));
+
+    // Clear glass with frosted stripes
+    let etching = Arc::new(CheckerTexture::from_color(
+        0.25,
+        Color::new(0., 0., 0.),
+        Color::new(0.5, 0.5, 0.5),
+    ));
+    world.add(make_box(
+        &Point3::new(0.8, 0., -2.05),
+        &Point3::new(3., 2.5, -2.),
+        &Dielectric::new_clear(1.5).with_roughness(etching).into(),
+    ));
+    world.add(Sphere::new(
+        Point3::new(1.9, 0.8, -3.5),
+        0.8,
+        Lambertian::new(Color::new(0.2, 0.3, 0.8)),
+    ));
+
+    for i in 0..4 {
+        world.add(Sphere::new(
+            Point3::new(i as f64 * 1.2 - 1.8, 0.45, 0.),
+            0.45,
+            Dielectric::rough(1.5, i as f64 / 3.),
+        ));
+    }
+
+    world.add(Quad::new(
+        Point3::new(-2., 5., -1.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 0., 2.),
+        DiffuseLight::new(Color::new(5., 5., 5.)),
+    ));
+
+    let mut cam = Camera::new(
+        16. / 9.,
+        600,
+        400,
+        50,
+        40.,
+        Point3::new(0., 2., 6.),
+        Point3::new(0., 0.8, -1.5),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.5, 0.6, 0.8),
+    );
+    cam.clamp_indirect = Some(10.);
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+// Car paint, brushed gold, velvet, frosted glass, a lamp and a marbled plastic, all made with the
+// same Principled material
+fn principled() {
+    let mut world = HittableList::new();
+
+    world.add(Quad::new(
+        Point3::new(-10., 0., -10.),
+        Vec3::new(0., 0., 20.),
+        Vec3::new(20., 0., 0.),
+        Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+    ));
+
+    let mut paint = Principled::new(Color::new(0.6, 0.02, 0.02));
+    paint.roughness = SolidColor::gray(0.4);
+    paint.clearcoat = SolidColor::gray(1.);
+    paint.clearcoat_roughness = SolidColor::gray(0.05);
+
+    let mut gold = Principled::new(Color::new(1., 0.77, 0.34));
+    gold.metallic = SolidColor::gray(1.);
+    gold.roughness = SolidColor::gray(0.3);
+
+    let mut velvet = Principled::new(Color::new(0.15, 0.05, 0.3));
+    velvet.roughness = SolidColor::gray(1.);
+    velvet.specular = SolidColor::gray(0.);
+    velvet.sheen = SolidColor::gray(1.);
+
+    let mut frosted = Principled::new(Color::new(0.9, 0.95, 1.));
+    frosted.transmission = SolidColor::gray(1.);
+    frosted.roughness = SolidColor::gray(0.25);
+
+    let mut lamp = Principled::new(Color::new(0.8, 0.8, 0.8));
+    lamp.emission = Arc::new(SolidColor::new(blackbody(3000.) * 4.));
+
+    // Glossy where the noise is dark, matte where it is light
+    let mut marbled = Principled::new(Color::new(0.2, 0.5, 0.8));
+    marbled.roughness = Arc::new(NoiseTexture::new(4.));
+
+    let materials = [paint, gold, velvet, frosted, lamp, marbled];
+    for (i, m) in materials.into_iter().enumerate() {
+        let x = (i % 3) as f64 * 1.3 - 1.3;
+        let z = -((i / 3) as f64) * 1.3;
+        world.add(Sphere::new(Point3::new(x, 0.55, z), 0.55, Material::Principled(m)));
+    }
+
+    world.add(Quad::new(
+        Point3::new(-2., 5., -1.),
+        Vec3::new(4., 0., 0.),
+        Vec3::new(0., 0., 2.),
+        DiffuseLight::new(Color::new(4., 4., 4.)),
+    ));
+
+    let cam = Camera::new(
+        16. / 9.,
+        600,
+        400,
+        50,
+        35.,
+        Point3::new(0., 2.5, 6.),
+        Point3::new(0., 0.4, -0.6),
+        Vec3::new(0., 1., 0.),
+        0.,
+        0.,
+        Color::new(0.3, 0.35, 0.45),
+    );
+
+    let mut pixels = init_pixels(&cam);
+    render_par(&cam, &world, &mut pixels, &vec![]);
+}
+
+fn final_scene(
+    image_width: i32,
+    samples_per_pixel: i32,
+    max_depth: i32,
+    integrator: Integrator,
+) {
     let mut boxes1 = HittableList::new();
     let ground = Lambertian::new(Color::new(0.48, 0.83, 0.53));
 
@@ -693,7 +1792,7 @@ fn final_scene(image_width: i32, samples_per_pixel: i32, max_depth: i32) {
         Vec3::new(-100., 270., 395.),
     ));
 
-    let cam = Camera::new(
+    let mut cam = Camera::new(
         1.0,
         image_width,
         samples_per_pixel,
@@ -706,6 +1805,7 @@ fn final_scene(image_width: i32, samples_per_pixel: i32, max_depth: i32) {
         0.,
         Color::new_zero(),
     );
+    cam.integrator = integrator;
 
     let mut pixels = init_pixels(&cam);
     render_par(&cam, &world, &mut pixels, &vec![]);
@@ -720,10 +1820,36 @@ fn main() {
         3 => earth(),
         4 => two_perlin_spheres(),
         5 => quads(),
-        6 => simple_light(),
-        7 => cornell_box(),
-        8 => cornell_smoke(),
-        9 => final_scene(800, 10000, 40),
-        _ => final_scene(400, 250, 4),
+        6 => simple_light(false),
+        7 => cornell_box(PathTracer::new()),
+        8 => cornell_smoke(PathTracer::new()),
+        9 => final_scene(800, 10000, 40, PathTracer::new()),
+        10 => cornell_box(DirectLighting::new()),
+        11 => cornell_smoke(Bdpt::new()),
+        12 => cornell_box(PhotonMapper::new(500_000, 50, 10.)),
+        13 => dispersion(),
+        14 => cornell_box(Mlt::new(PathTracer::new(), 100_000, 1000, 0.01, 0.3)),
+        15 => cornell_box(Preview::ambient_occlusion(4, 100.)),
+        16 => cornell_box(Preview::normals()),
+        17 => city_lights(),
+        18 => simple_light(true),
+        19 => clouds(),
+        20 => absorbing_glass(),
+        21 => glass_of_water(),
+        22 => subsurface(),
+        23 => fire(),
+        24 => stage_lights(),
+        25 => light_panels(),
+        26 => downlights(),
+        27 => living_room(),
+        28 => rough_metals(),
+        29 => frosted_glass(),
+        30 => principled(),
+        31 => cornell_box(Preview::uv()),
+        32 => cornell_box(Preview::distance(1600.)),
+        33 => cornell_box(Preview::material_index()),
+        34 => final_scene(400, 1, 4, Preview::bvh_traversal(64)),
+        35 => hazy_beams(),
+        _ => final_scene(400, 250, 4, PathTracer::new()),
     }
 }
@@ -3,12 +3,18 @@ use std::{f64::consts::PI, sync::Arc};
 use crate::{
     color::Color,
     hittable::HitRecord,
-    pdf::{AnyPDF, CosinePDF, SpherePDF},
+    ies::IesProfile,
+    medium::InteriorMedium,
+    microfacet::{dielectric_reflectance, half_vector, to_local, ConductorFresnel, TrowbridgeReitz},
+    onb::Onb,
+    pdf::{AnyPDF, CosinePDF, DielectricPDF, FuzzPDF, MicrofacetPDF, MixturePDF, PhasePDF, SpherePDF, PDF},
+    phase::PhaseFunction,
+    photometry::chromaticity,
     ray::Ray,
     texture::{SolidColor, Texture},
     utils::random_double,
     vec3::{
-        dot, random_unit_vector, reflect, refract, unit_vector, Point3,
+        dot, reflect, refract, unit_vector, Point3, Vec3,
     },
 };
 
@@ -26,9 +32,84 @@ pub struct ScatterRecord {
 pub enum Material {
     Lambertian(Lambertian),
     Metal(Metal),
+    Conductor(Conductor),
     Dielectric(Dielectric),
+    Principled(Principled),
     DiffuseLight(DiffuseLight),
     Isotropic(Isotropic),
+    Anisotropic(Anisotropic),
+    Subsurface(Subsurface),
+    EmissiveVolume(EmissiveVolume),
+}
+
+impl Material {
+    // Surface color at rec ignoring lighting, used as a guide by the denoiser
+    pub fn albedo(&self, rec: &HitRecord) -> Color {
+        match self {
+            Material::Lambertian(l) => l.texture.value(rec.u, rec.v, &rec.p),
+            Material::Metal(m) => m.albedo,
+            Material::Conductor(c) => c.fresnel.value(1.),
+            Material::Dielectric(d) => d.tint,
+            Material::Principled(p) => p.base_color.value(rec.u, rec.v, &rec.p),
+            Material::DiffuseLight(_) => Color::new(1., 1., 1.),
+            Material::Isotropic(i) => i.albedo.value(rec.u, rec.v, &rec.p),
+            Material::Anisotropic(a) => a.albedo.value(rec.u, rec.v, &rec.p),
+            Material::Subsurface(s) => s.albedo,
+            Material::EmissiveVolume(e) => e.phase_function.albedo(rec),
+        }
+    }
+
+    // Phase functions of participating media, which scatter inside volumes rather than at surfaces
+    pub fn is_volumetric(&self) -> bool {
+        matches!(
+            self,
+            Material::Isotropic(_) | Material::Anisotropic(_) | Material::EmissiveVolume(_)
+        )
+    }
+
+    pub fn is_two_sided(&self) -> bool {
+        matches!(self, Material::DiffuseLight(l) if l.two_sided)
+    }
+
+    // Materials that give off light, which are collected into the lights to sample
+    pub fn is_emitter(&self) -> bool {
+        match self {
+            Material::DiffuseLight(_) => true,
+            Material::Principled(p) => !p.emission.is_black(),
+            _ => false,
+        }
+    }
+
+    // The texture of an emitter whose brightness changes over its surface
+    pub fn emission_texture(&self) -> Option<&Arc<Texture>> {
+        let emit = match self {
+            Material::DiffuseLight(l) => &l.emit,
+            Material::Principled(p) if self.is_emitter() => &p.emission,
+            _ => return None,
+        };
+
+        match **emit {
+            Texture::Solid(_) => None,
+            _ => Some(emit),
+        }
+    }
+
+    // Name of the light group an emitter belongs to, None for ungrouped lights and non emitters
+    pub fn light_group(&self) -> Option<&str> {
+        match self {
+            Material::DiffuseLight(l) => l.group.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Radiance leaving the front of an emitter at (u, v, p), zero for materials that don't emit
+    pub fn emission(&self, u: f64, v: f64, p: &Point3) -> Color {
+        match self {
+            Material::DiffuseLight(l) => l.emit.value(u, v, p),
+            Material::Principled(m) => m.emission.value(u, v, p),
+            _ => Color::new_zero(),
+        }
+    }
 }
 
 impl MatFn for Material {
@@ -36,15 +117,22 @@ impl MatFn for Material {
         match self {
             Material::Lambertian(l) => l.scatter(r_in, rec),
             Material::Metal(m) => m.scatter(r_in, rec),
+            Material::Conductor(c) => c.scatter(r_in, rec),
             Material::Dielectric(d) => d.scatter(r_in, rec),
+            Material::Principled(p) => p.scatter(r_in, rec),
             Material::DiffuseLight(d) => d.scatter(r_in, rec),
             Material::Isotropic(d) => d.scatter(r_in, rec),
+            Material::Anisotropic(d) => d.scatter(r_in, rec),
+            Material::Subsurface(d) => d.scatter(r_in, rec),
+            Material::EmissiveVolume(d) => d.scatter(r_in, rec),
         }
     }
 
     fn emitted(&self, r_in: &Ray, rec: &HitRecord, u: f64, v: f64, p: &Point3) -> Color {
         match self {
             Material::DiffuseLight(l) => l.emitted(r_in, rec, u, v, p),
+            Material::EmissiveVolume(e) => e.emitted(r_in, rec, u, v, p),
+            Material::Principled(m) => m.emitted(r_in, rec, u, v, p),
             _ => Color::new_zero(),
         }
     }
@@ -52,10 +140,32 @@ impl MatFn for Material {
     fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
         match self {
             Material::Lambertian(l) => l.scattering_pdf(r_in, rec, scattered),
+            Material::Metal(m) => m.scattering_pdf(r_in, rec, scattered),
+            Material::Conductor(c) => c.scattering_pdf(r_in, rec, scattered),
+            Material::Dielectric(d) => d.scattering_pdf(r_in, rec, scattered),
+            Material::Principled(p) => p.scattering_pdf(r_in, rec, scattered),
             Material::Isotropic(l) => l.scattering_pdf(r_in, rec, scattered),
+            Material::Anisotropic(l) => l.scattering_pdf(r_in, rec, scattered),
+            Material::Subsurface(l) => l.scattering_pdf(r_in, rec, scattered),
+            Material::EmissiveVolume(l) => l.scattering_pdf(r_in, rec, scattered),
             _ => 0.,
         }
     }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        match self {
+            Material::Lambertian(l) => l.eval(r_in, rec, scattered),
+            Material::Metal(m) => m.eval(r_in, rec, scattered),
+            Material::Conductor(c) => c.eval(r_in, rec, scattered),
+            Material::Dielectric(d) => d.eval(r_in, rec, scattered),
+            Material::Principled(p) => p.eval(r_in, rec, scattered),
+            Material::Isotropic(l) => l.eval(r_in, rec, scattered),
+            Material::Anisotropic(l) => l.eval(r_in, rec, scattered),
+            Material::Subsurface(l) => l.eval(r_in, rec, scattered),
+            Material::EmissiveVolume(l) => l.eval(r_in, rec, scattered),
+            _ => Color::new_zero(),
+        }
+    }
 }
 
 pub trait MatFn {
@@ -67,9 +177,17 @@ pub trait MatFn {
         Color::new_zero()
     }
 
+    // The density of scattered under the PdfPtr scatter returns, without sampling anything.
+    // Zero for materials that only skip rays.
     fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
         0.
     }
+
+    // The BRDF times the cosine of the scattered direction (or the phase function for volumes).
+    // Only defined for materials that scatter through a pdf, mirror-like ones return zero.
+    fn eval(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Color {
+        Color::new_zero()
+    }
 }
 
 #[derive(Clone)]
@@ -106,8 +224,14 @@ impl MatFn for Lambertian {
             cos_theta / PI
         }
     }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.texture.value(rec.u, rec.v, &rec.p) * self.scattering_pdf(r_in, rec, scattered)
+    }
 }
 
+// A mirror blurred by jittering reflections by fuzz, the metal from Ray Tracing in One Weekend.
+// See Conductor for metals with Fresnel reflection and a microfacet BRDF.
 #[derive(Clone)]
 pub struct Metal {
     albedo: Color,
@@ -119,102 +243,721 @@ impl Metal {
         let fuzz = if f < 1. { f } else { 1. };
         Material::Metal(Metal { albedo, fuzz })
     }
+
+    fn reflected(r_in: &Ray, rec: &HitRecord) -> Vec3 {
+        reflect(&unit_vector(&r_in.direction()), &rec.normal)
+    }
 }
 
 impl MatFn for Metal {
     fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
-        let reflected = reflect(&unit_vector(&r_in.direction()), &rec.normal);
-        let reflected = unit_vector(&reflected) + (self.fuzz * random_unit_vector());
+        let reflected = Self::reflected(r_in, rec);
+
+        if self.fuzz <= 0. {
+            return Some(ScatterRecord {
+                attenuation: self.albedo,
+                data: SrecData::SkipRay(Ray::new_timed(rec.p, reflected, r_in.time())),
+            });
+        }
 
         Some(ScatterRecord {
             attenuation: self.albedo,
-            data: SrecData::SkipRay(Ray::new_timed(rec.p, reflected, r_in.time())),
+            data: SrecData::PdfPtr(Box::new(AnyPDF::Fuzz(FuzzPDF::new(&reflected, self.fuzz)))),
+        })
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        FuzzPDF::new(&Self::reflected(r_in, rec), self.fuzz).value(&scattered.direction())
+    }
+
+    // The BRDF is whatever makes the fuzzy reflections reflect albedo, with the ones jittered
+    // into the surface absorbed
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        if dot(&scattered.direction(), &rec.normal) <= 0. {
+            return Color::new_zero();
+        }
+        self.albedo * self.scattering_pdf(r_in, rec, scattered)
+    }
+}
+
+// A metal with microfacets spread by the GGX distribution, which has a real BRDF, so rough
+// metals can be light sampled and don't gain or lose energy with roughness
+#[derive(Clone)]
+pub struct Conductor {
+    fresnel: ConductorFresnel,
+    distribution: TrowbridgeReitz,
+}
+
+impl Conductor {
+    // Reflects albedo straight on, going to white at grazing angles
+    pub fn new(albedo: Color, roughness: f64) -> Material {
+        Material::Conductor(Conductor {
+            fresnel: ConductorFresnel::Schlick(albedo),
+            distribution: TrowbridgeReitz::new(roughness),
         })
+    }
 
+    // A real metal from its measured complex index of refraction eta + ik at red, green and blue
+    pub fn from_ior(eta: Color, k: Color, roughness: f64) -> Material {
+        Material::Conductor(Conductor {
+            fresnel: ConductorFresnel::Complex { eta, k },
+            distribution: TrowbridgeReitz::new(roughness),
+        })
+    }
+
+    // wo and wi in the local frame of the normal, along with the half vector between them
+    fn frame(r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Option<(Vec3, Vec3, Vec3)> {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&rec.normal);
+
+        let wo = to_local(&uvw, &-unit_vector(&r_in.direction()));
+        let wi = to_local(&uvw, &unit_vector(&scattered.direction()));
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return None;
+        }
+
+        Some((wo, wi, unit_vector(&(wo + wi))))
+    }
+}
+
+impl MatFn for Conductor {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        let direction = unit_vector(&r_in.direction());
+
+        if self.distribution.is_smooth() {
+            let cos_theta = dot(&-direction, &rec.normal);
+            return Some(ScatterRecord {
+                attenuation: self.fresnel.value(cos_theta),
+                data: SrecData::SkipRay(Ray::new_timed(
+                    rec.p,
+                    reflect(&direction, &rec.normal),
+                    r_in.time(),
+                )),
+            });
+        }
+
+        Some(ScatterRecord {
+            attenuation: Color::new(1., 1., 1.), // the Fresnel term depends on the direction, see eval
+            data: SrecData::PdfPtr(Box::new(MicrofacetPDF::new(
+                self.distribution,
+                &rec.normal,
+                &-direction,
+            ))),
+        })
     }
 
     fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
-        1. / (4. * PI)
+        match Self::frame(r_in, rec, scattered) {
+            Some((wo, _, wm)) => self.distribution.pdf(&wo, &wm) / (4. * dot(&wo, &wm)),
+            None => 0.,
+        }
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        match Self::frame(r_in, rec, scattered) {
+            // D F G / (4 cos_o cos_i), times cos_i
+            Some((wo, wi, wm)) => {
+                self.fresnel.value(dot(&wo, &wm)) * self.distribution.d(&wm) * self.distribution.g(&wo, &wi)
+                    / (4. * wo.z())
+            }
+            None => Color::new_zero(),
+        }
     }
 }
 
+// One material for everything from plastic and paint to metal, glass, cloth and lamps, with the
+// parameters of the Disney principled BSDF that other tools export. Every parameter is a texture,
+// the scalar ones using the average of their channels (see SolidColor::gray). Set the fields after
+// Principled::new and wrap it in Material::Principled.
+#[derive(Clone)]
+pub struct Principled {
+    pub base_color: Arc<Texture>,
+    pub metallic: Arc<Texture>,  // 0 for dielectrics, 1 for metals tinted by base_color
+    pub roughness: Arc<Texture>, // GGX roughness of the specular and transmission lobes
+    pub specular: Arc<Texture>,  // reflectance of dielectrics, 0.5 is the usual 4%
+    pub clearcoat: Arc<Texture>, // strength of a clear varnish layer on top
+    pub clearcoat_roughness: Arc<Texture>,
+    pub sheen: Arc<Texture>, // soft glow at grazing angles like on cloth, tinted towards base_color
+    pub transmission: Arc<Texture>, // how much of the dielectric part is glass instead of diffuse
+    pub emission: Arc<Texture>,
+    pub ior: f64, // index of refraction of transmission, see scatter_between
+}
+
+// The parameters at one point of the surface, with the weights of the lobes they give
+struct PrincipledSample {
+    base_color: Color,
+    roughness: f64,
+    specular_f0: Color,  // Fresnel reflectance straight on, white for dielectrics and base_color for metals
+    dielectric_f0: f64,  // just the dielectric part, which decides how much light reaches the diffuse
+    clearcoat: f64,
+    clearcoat_roughness: f64,
+    sheen: f64,
+    diffuse: f64,      // weight of diffuse and sheen
+    transmission: f64, // weight of glass, which scatter picks with this probability
+}
+
+impl Principled {
+    pub fn new(base_color: Color) -> Principled {
+        Self::from_texture(Arc::new(SolidColor::new(base_color)))
+    }
+
+    pub fn from_texture(base_color: Arc<Texture>) -> Principled {
+        Principled {
+            base_color,
+            metallic: SolidColor::gray(0.),
+            roughness: SolidColor::gray(0.5),
+            specular: SolidColor::gray(0.5),
+            clearcoat: SolidColor::gray(0.),
+            clearcoat_roughness: SolidColor::gray(0.1),
+            sheen: SolidColor::gray(0.),
+            transmission: SolidColor::gray(0.),
+            emission: Arc::new(SolidColor::new(Color::new_zero())),
+            ior: 1.5,
+        }
+    }
+
+    fn sample(&self, rec: &HitRecord) -> PrincipledSample {
+        let base_color = self.base_color.value(rec.u, rec.v, &rec.p);
+        let metallic = scalar(&self.metallic, rec).clamp(0., 1.);
+        let transmission = scalar(&self.transmission, rec).clamp(0., 1.);
+        let dielectric_f0 = (0.08 * scalar(&self.specular, rec)).clamp(0., 1.);
+
+        PrincipledSample {
+            base_color,
+            // Perfectly smooth reflections would need their own delta lobe
+            roughness: scalar(&self.roughness, rec).max(0.02),
+            specular_f0: (1. - metallic) * Color::new(dielectric_f0, dielectric_f0, dielectric_f0)
+                + metallic * base_color,
+            dielectric_f0,
+            clearcoat: scalar(&self.clearcoat, rec).clamp(0., 1.),
+            clearcoat_roughness: scalar(&self.clearcoat_roughness, rec).max(0.02),
+            sheen: scalar(&self.sheen, rec).clamp(0., 1.),
+            diffuse: (1. - metallic) * (1. - transmission),
+            transmission: (1. - metallic) * transmission,
+        }
+    }
+
+    // Every lobe but glass: diffuse, specular and clear coat
+    fn reflection_pdf(&self, r_in: &Ray, rec: &HitRecord, s: &PrincipledSample) -> AnyPDF {
+        let wo = -r_in.direction();
+
+        let base = AnyPDF::Mixed(MixturePDF::weighted(
+            Box::new(CosinePDF::new(&rec.normal)),
+            Box::new(MicrofacetPDF::new(
+                TrowbridgeReitz::new(s.roughness),
+                &rec.normal,
+                &wo,
+            )),
+            0.5 * s.diffuse / (1. - s.transmission),
+        ));
+
+        if s.clearcoat > 0. {
+            AnyPDF::Mixed(MixturePDF::weighted(
+                Box::new(MicrofacetPDF::new(
+                    TrowbridgeReitz::new(s.clearcoat_roughness),
+                    &rec.normal,
+                    &wo,
+                )),
+                Box::new(base),
+                0.2 * s.clearcoat,
+            ))
+        } else {
+            base
+        }
+    }
+
+    pub fn emitted(&self, _r_in: &Ray, rec: &HitRecord, u: f64, v: f64, p: &Point3) -> Color {
+        match rec.front_face {
+            true => self.emission.value(u, v, p),
+            false => Color::new_zero(),
+        }
+    }
+
+    // scatter, with glass refracting against a material of index outside_ir
+    pub fn scatter_between(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        outside_ir: f64,
+    ) -> Option<ScatterRecord> {
+        let s = self.sample(rec);
+
+        // Glass is picked as often as it contributes, which leaves just its own color
+        if random_double() < s.transmission {
+            let refraction_ratio = if rec.front_face {
+                outside_ir / self.ior
+            } else {
+                self.ior / outside_ir
+            };
+            let (direction, shadowing) =
+                reflect_or_refract(r_in, rec, refraction_ratio, microfacets(s.roughness))?;
+
+            return Some(ScatterRecord {
+                attenuation: s.base_color * shadowing,
+                data: SrecData::SkipRay(Ray::new_timed(rec.p, direction, r_in.time())),
+            });
+        }
+
+        Some(ScatterRecord {
+            attenuation: Color::new(1., 1., 1.), // changes with direction, see eval
+            data: SrecData::PdfPtr(Box::new(self.reflection_pdf(r_in, rec, &s))),
+        })
+    }
+}
+
+impl MatFn for Principled {
+    // Assumes air on the other side like Dielectric, PathMedium knows better
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        self.scatter_between(r_in, rec, 1.)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let s = self.sample(rec);
+        if 1. - s.transmission <= 0. {
+            return 0.;
+        }
+
+        self.reflection_pdf(r_in, rec, &s).value(&scattered.direction())
+    }
+
+    // Only used once scatter has picked the reflection lobes, so divided by the chance it did
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        let s = self.sample(rec);
+        let reflection = 1. - s.transmission;
+        if reflection <= 0. {
+            return Color::new_zero();
+        }
+
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&rec.normal);
+        let wo = to_local(&uvw, &-unit_vector(&r_in.direction()));
+        let wi = to_local(&uvw, &unit_vector(&scattered.direction()));
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return Color::new_zero();
+        }
+        let wm = unit_vector(&(wo + wi));
+        let cos_d = dot(&wi, &wm);
+
+        let schlick = |f0: f64, cos: f64| f0 + (1. - f0) * (1. - cos).clamp(0., 1.).powi(5);
+        let microfacet = |roughness: f64| {
+            let distribution = TrowbridgeReitz::new(roughness);
+            distribution.d(&wm) * distribution.g(&wo, &wi) / (4. * wo.z() * wi.z())
+        };
+
+        // Diffuse and sheen only get the light the specular reflection lets through
+        let sheen_color = 0.5 * (Color::new(1., 1., 1.) + chromaticity(&s.base_color));
+        let base = s.diffuse
+            * (1. - schlick(s.dielectric_f0, wo.z()))
+            * (s.base_color / PI + s.sheen * sheen_color * (1. - cos_d).powi(5));
+
+        let specular = ConductorFresnel::Schlick(s.specular_f0).value(cos_d) * microfacet(s.roughness);
+
+        // And everything lies under the clear coat
+        let coat = s.clearcoat * schlick(0.04, cos_d) * microfacet(s.clearcoat_roughness);
+        let under_coat = 1. - s.clearcoat * schlick(0.04, wo.z());
+
+        (under_coat * (base + specular) + Color::new(coat, coat, coat)) / reflection * wi.z()
+    }
+}
+
+// Wavelength dependent index of refraction, with wavelengths in micrometers
+#[derive(Clone)]
+pub enum Dispersion {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    pub fn ir(&self, lambda_nm: f64) -> f64 {
+        let lambda = lambda_nm / 1000.;
+        let lambda2 = lambda * lambda;
+
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / lambda2,
+            Dispersion::Sellmeier { b, c } => (1.
+                + (0..3)
+                    .map(|i| b[i] * lambda2 / (lambda2 - c[i]))
+                    .sum::<f64>())
+            .sqrt(),
+        }
+    }
+}
+
+// Sodium d-line, the wavelength indices of refraction are usually quoted at
+const LAMBDA_D: f64 = 587.6;
+
 #[derive(Clone)]
 pub struct Dielectric {
     tint: Color,
     ir: f64, // Index of Refraction
+    dispersion: Option<Dispersion>,
+    medium: Option<Arc<InteriorMedium>>,
+    priority: u32,
+    roughness: Option<Arc<Texture>>, // GGX roughness from 0 to 1 over the surface, None for smooth
 }
 
 impl Dielectric {
-    pub fn new(ir: f64, tint: Color) -> Material {
-        Material::Dielectric(Dielectric { tint, ir })
+    pub fn new(ir: f64, tint: Color) -> Dielectric {
+        Dielectric {
+            tint,
+            ir,
+            dispersion: None,
+            medium: None,
+            priority: 0,
+            roughness: None,
+        }
     }
 
-    pub fn new_clear(ir: f64) -> Material {
+    // Glass or liquid whose color depends on how far light travels through it
+    pub fn with_medium(ir: f64, medium: InteriorMedium) -> Dielectric {
+        Dielectric {
+            medium: Some(Arc::new(medium)),
+            ..Self::new_clear(ir)
+        }
+    }
+
+    pub fn new_clear(ir: f64) -> Dielectric {
         Self::new(ir, Color::new(1., 1., 1.))
     }
 
-    fn reflectance(&self, cosine: f64, ref_idx: f64) -> f64 {
-        // Use Schlick's approximation for reflectance
+    // Frosted glass, blurring what is seen through it more the higher roughness is
+    pub fn rough(ir: f64, roughness: f64) -> Dielectric {
+        let roughness = Color::new(roughness, roughness, roughness);
+        Self::new_clear(ir).with_roughness(Arc::new(SolidColor::new(roughness)))
+    }
 
-        let r0 = (1. - ref_idx) / (1. + ref_idx);
-        let r0 = r0 * r0;
+    // Roughens the surface by the average of the texture's channels, from 0 for smooth to 1, for
+    // etched or partly frosted glass
+    pub fn with_roughness(self, roughness: Arc<Texture>) -> Dielectric {
+        Dielectric {
+            roughness: Some(roughness),
+            ..self
+        }
+    }
 
-        r0 + (1. - r0) * (1. - cosine).powf(5.)
+    // Only spectral rendering sees the dispersion, RGB rendering uses the index at the d-line
+    pub fn new_dispersive(dispersion: Dispersion, tint: Color) -> Dielectric {
+        Dielectric {
+            ir: dispersion.ir(LAMBDA_D),
+            dispersion: Some(dispersion),
+            ..Self::new(1., tint)
+        }
     }
-}
 
-impl MatFn for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
-        let refraction_ratio = if rec.front_face {
-            1.0 / self.ir
-        } else {
-            self.ir
+    // Clear glass whose index follows Cauchy's equation a + b / lambda^2, with lambda in
+    // micrometers, which is close enough for most glasses over the visible range
+    pub fn cauchy(a: f64, b: f64) -> Dielectric {
+        Self::new_dispersive(Dispersion::Cauchy { a, b }, Color::new(1., 1., 1.))
+    }
+
+    // Dielectrics with a higher priority win where they overlap others, so a liquid can fill a
+    // container by overlapping its walls
+    pub fn with_priority(self, priority: u32) -> Dielectric {
+        Dielectric { priority, ..self }
+    }
+
+    pub fn medium(&self) -> Option<&InteriorMedium> {
+        self.medium.as_deref()
+    }
+
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    // Copies with the same interior count as one material, see medium::same
+    pub fn same_as(&self, other: &Dielectric) -> bool {
+        let same_medium = match (&self.medium, &other.medium) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
         };
 
-        let unit_direction = unit_vector(&r_in.direction());
-        let cos_theta = dot(&-unit_direction, &rec.normal).min(1.);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        self.ir == other.ir && self.priority == other.priority && same_medium
+    }
 
-        let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let direction =
-            if cannot_refract || self.reflectance(cos_theta, refraction_ratio) > random_double() {
-                reflect(&unit_direction, &rec.normal)
-            } else {
-                refract(&unit_direction, &rec.normal, refraction_ratio)
-            };
+    pub fn ir_at(&self, lambda: Option<f64>) -> f64 {
+        match (&self.dispersion, lambda) {
+            (Some(dispersion), Some(lambda)) => dispersion.ir(lambda),
+            _ => self.ir,
+        }
+    }
+
+    // Reflects or refracts r_in at a boundary with a material of index outside_ir
+    pub fn scatter_between(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        outside_ir: f64,
+    ) -> Option<ScatterRecord> {
+        if let Some(pdf) = self.rough_pdf(r_in, rec, outside_ir) {
+            return Some(ScatterRecord {
+                attenuation: self.tint, // the Fresnel term depends on the direction, see eval
+                data: SrecData::PdfPtr(Box::new(AnyPDF::Dielectric(pdf))),
+            });
+        }
+
+        let refraction_ratio = self.refraction_ratio(r_in, rec, outside_ir);
+        let (direction, shadowing) = reflect_or_refract(r_in, rec, refraction_ratio, None)?;
 
         Some(ScatterRecord {
-            attenuation: self.tint,
+            attenuation: self.tint * shadowing,
             data: SrecData::SkipRay(Ray::new_timed(rec.p, direction, r_in.time())),
         })
+    }
 
+    pub fn scattering_pdf_between(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        scattered: &Ray,
+        outside_ir: f64,
+    ) -> f64 {
+        self.rough_pdf(r_in, rec, outside_ir)
+            .map_or(0., |pdf| pdf.value(&scattered.direction()))
+    }
+
+    // The BSDF of rough glass times the cosine, zero for smooth glass which only scatters along
+    // the directions scatter picks
+    pub fn eval_between(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        scattered: &Ray,
+        outside_ir: f64,
+    ) -> Color {
+        let distribution = match self.microfacets(rec) {
+            Some(distribution) => distribution,
+            None => return Color::new_zero(),
+        };
+        let eta = self.refraction_ratio(r_in, rec, outside_ir);
+
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&rec.normal);
+        let wo = to_local(&uvw, &-unit_vector(&r_in.direction()));
+        let wi = to_local(&uvw, &unit_vector(&scattered.direction()));
+        let wm = match half_vector(&wo, &wi, eta) {
+            Some(wm) => wm,
+            None => return Color::new_zero(),
+        };
+
+        let reflectance = dielectric_reflectance(dot(&wo, &wm), eta);
+        let dg = distribution.d(&wm) * distribution.g(&wo, &wi);
+
+        let f = if wi.z() > 0. {
+            // D F G / (4 cos_o cos_i), times cos_i
+            reflectance * dg / (4. * wo.z())
+        } else {
+            // D (1 - F) G |wi.wm| |wo.wm| / (cos_o cos_i (wi.wm + eta wo.wm)^2), times cos_i. Like
+            // smooth glass this leaves out the 1 / eta^2 that concentrates radiance on the dense side.
+            let denom = dot(&wi, &wm) + eta * dot(&wo, &wm);
+            (1. - reflectance) * dg * (dot(&wi, &wm) * dot(&wo, &wm)).abs()
+                / (wo.z() * denom * denom)
+        };
+
+        self.tint * f
+    }
+
+    // The index r_in comes from over the index it goes into
+    fn refraction_ratio(&self, r_in: &Ray, rec: &HitRecord, outside_ir: f64) -> f64 {
+        let ir = self.ir_at(r_in.wavelength());
+        if rec.front_face {
+            outside_ir / ir
+        } else {
+            ir / outside_ir
+        }
+    }
+
+    fn microfacets(&self, rec: &HitRecord) -> Option<TrowbridgeReitz> {
+        self.roughness
+            .as_ref()
+            .and_then(|roughness| microfacets(scalar(roughness, rec)))
+    }
+
+    // Where rough glass sends light, None where it is smooth enough to be a mirror or matches the
+    // index outside so that there is no boundary to scatter off
+    fn rough_pdf(&self, r_in: &Ray, rec: &HitRecord, outside_ir: f64) -> Option<DielectricPDF> {
+        let eta = self.refraction_ratio(r_in, rec, outside_ir);
+        if eta == 1. {
+            return None;
+        }
+
+        self.microfacets(rec).map(|distribution| {
+            DielectricPDF::new(distribution, &rec.normal, &-r_in.direction(), eta)
+        })
+    }
+}
+
+// The microfacet distribution for roughness, None where the surface is smooth enough to be a mirror
+fn microfacets(roughness: f64) -> Option<TrowbridgeReitz> {
+    let distribution = TrowbridgeReitz::new(roughness);
+
+    if distribution.is_smooth() {
+        None
+    } else {
+        Some(distribution)
+    }
+}
+
+// A texture used as a single number, the average of its channels
+fn scalar(texture: &Texture, rec: &HitRecord) -> f64 {
+    let c = texture.value(rec.u, rec.v, &rec.p);
+    (c.x() + c.y() + c.z()) / 3.
+}
+
+// Reflects or refracts r_in at a boundary where refraction_ratio is the index it comes from over
+// the index it goes into. Rough surfaces do it off a microfacet visible from r_in, following
+// Walter et al. "Microfacet Models for Refraction through Rough Surfaces". Returns the new
+// direction and how much light makes it, None when a microfacet sends it to the wrong side.
+fn reflect_or_refract(
+    r_in: &Ray,
+    rec: &HitRecord,
+    refraction_ratio: f64,
+    microfacets: Option<TrowbridgeReitz>,
+) -> Option<(Vec3, f64)> {
+    let unit_direction = unit_vector(&r_in.direction());
+
+    let microfacets = microfacets.map(|distribution| {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&rec.normal);
+        let wo = to_local(&uvw, &-unit_direction);
+        (distribution, uvw, wo)
+    });
+    let normal = match &microfacets {
+        Some((distribution, uvw, wo)) => uvw.local_vec(&distribution.sample_wm(wo)),
+        None => rec.normal,
+    };
+
+    let cos_theta = dot(&-unit_direction, &normal);
+    let reflected = dielectric_reflectance(cos_theta, refraction_ratio) > random_double();
+    let direction = if reflected {
+        reflect(&unit_direction, &normal)
+    } else {
+        refract(&unit_direction, &normal, refraction_ratio)
+    };
+
+    match microfacets {
+        Some((distribution, uvw, wo)) => {
+            // Light sent to the wrong side of the surface by its microfacet is lost
+            let wi = to_local(&uvw, &unit_vector(&direction));
+            if (wi.z() > 0.) != reflected {
+                return None;
+            }
+
+            // Sampling visible normals leaves only the shadowing of the outgoing direction
+            Some((direction, distribution.g(&wo, &wi) / distribution.g1(&wo)))
+        }
+        None => Some((direction, 1.)),
+    }
+}
+
+impl From<Dielectric> for Material {
+    fn from(d: Dielectric) -> Material {
+        Material::Dielectric(d)
+    }
+}
+
+impl MatFn for Dielectric {
+    // Assumes air on the other side, PathMedium knows better inside nested dielectrics
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        self.scatter_between(r_in, rec, 1.)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.scattering_pdf_between(r_in, rec, scattered, 1.)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.eval_between(r_in, rec, scattered, 1.)
     }
 }
 
 #[derive(Clone)]
 pub struct DiffuseLight {
     emit: Arc<Texture>,
+    group: Option<String>, // light group for per light output, None for the default group
+    two_sided: bool,
+    profile: Option<Arc<IesProfile>>,
 }
 
 impl DiffuseLight {
-    pub fn new(c: Color) -> Material {
-        Material::DiffuseLight(DiffuseLight {
+    pub fn new(c: Color) -> DiffuseLight {
+        DiffuseLight {
             emit: Arc::new(SolidColor::new(c)),
-        })
+            group: None,
+            two_sided: false,
+            profile: None,
+        }
+    }
+
+    // An emitter like a screen or a softbox. Quads with a textured light pick points to sample
+    // by how bright the texture is there.
+    pub fn from_texture(emit: Arc<Texture>) -> DiffuseLight {
+        DiffuseLight {
+            emit,
+            group: None,
+            two_sided: false,
+            profile: None,
+        }
+    }
+
+    // A light whose contribution is written to its own image when rendering light groups
+    pub fn new_in_group(c: Color, group: &str) -> DiffuseLight {
+        DiffuseLight {
+            emit: Arc::new(SolidColor::new(c)),
+            group: Some(group.to_string()),
+            two_sided: false,
+            profile: None,
+        }
+    }
+
+    // Emits from the back face too
+    pub fn two_sided(self) -> DiffuseLight {
+        DiffuseLight {
+            two_sided: true,
+            ..self
+        }
     }
 
-    pub fn _from_texture(emit: Arc<Texture>) -> Material {
-        Material::DiffuseLight(DiffuseLight { emit })
+    // Shapes the emission by a fixture's photometric profile, with the nadir along the surface
+    // normal. The light is redistributed, not dimmed: the surface gives off as much power as it
+    // would without the profile.
+    pub fn with_profile(self, profile: Arc<IesProfile>) -> DiffuseLight {
+        DiffuseLight {
+            profile: Some(profile),
+            ..self
+        }
     }
 
     pub fn emitted(&self, r_in: &Ray, rec: &HitRecord, u: f64, v: f64, p: &Point3) -> Color {
-        match rec.front_face {
-            true => self.emit.value(u, v, p),
-            false => Color::new_zero(),
+        if !(rec.front_face || self.two_sided) {
+            return Color::new_zero();
+        }
+
+        // rec.normal faces back along r_in, out of the side that is emitting
+        match &self.profile {
+            Some(profile) => {
+                // A plain emitter of radiance L gives off pi L per unit area. Spread that over
+                // the profile, and divide its intensity by the cosine to get back to radiance.
+                let direction = unit_vector(&-r_in.direction());
+                let cos_theta = dot(&direction, &rec.normal);
+                if cos_theta <= 1e-4 {
+                    return Color::new_zero();
+                }
+                let shape = profile.value(&direction, &rec.normal) * PI
+                    / (profile.solid_angle() * cos_theta);
+                self.emit.value(u, v, p) * shape
+            }
+            None => self.emit.value(u, v, p),
         }
     }
 }
 
+impl From<DiffuseLight> for Material {
+    fn from(l: DiffuseLight) -> Material {
+        Material::DiffuseLight(l)
+    }
+}
+
 impl MatFn for DiffuseLight {
     fn scatter(&self, _r_in: &Ray, _rec: &HitRecord) -> Option<ScatterRecord> {
         None
@@ -245,4 +988,138 @@ impl MatFn for Isotropic {
             data: SrecData::PdfPtr(Box::new(AnyPDF::Sphere(SpherePDF))),
         })
     }
+
+    fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        1. / (4. * PI)
+    }
+
+    fn eval(&self, _r_in: &Ray, rec: &HitRecord, _scattered: &Ray) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p) / (4. * PI)
+    }
+}
+
+// A phase function that prefers some directions over others, for media like haze, clouds and air
+#[derive(Clone)]
+pub struct Anisotropic {
+    albedo: Arc<Texture>,
+    phase: PhaseFunction,
+}
+
+impl Anisotropic {
+    pub fn new(c: Color, phase: PhaseFunction) -> Material {
+        Material::Anisotropic(Anisotropic {
+            albedo: Arc::new(SolidColor::new(c)),
+            phase,
+        })
+    }
+
+    pub fn from_texture(albedo: Arc<Texture>, phase: PhaseFunction) -> Material {
+        Material::Anisotropic(Anisotropic { albedo, phase })
+    }
+
+    fn cos_theta(r_in: &Ray, scattered: &Ray) -> f64 {
+        dot(
+            &unit_vector(&r_in.direction()),
+            &unit_vector(&scattered.direction()),
+        )
+    }
+}
+
+impl MatFn for Anisotropic {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: self.albedo.value(rec.u, rec.v, &rec.p),
+            data: SrecData::PdfPtr(Box::new(PhasePDF::new(self.phase, &r_in.direction()))),
+        })
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, _rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.phase.pdf(Self::cos_theta(r_in, scattered))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.albedo.value(rec.u, rec.v, &rec.p) * self.phase.value(Self::cos_theta(r_in, scattered))
+    }
+}
+
+// Translucent materials like wax, marble, milk and skin. Light passes diffusely through the
+// surface and random walks through an InteriorMedium::subsurface until it finds its way out again,
+// which needs the path tracers' PathMedium to follow it inside.
+#[derive(Clone)]
+pub struct Subsurface {
+    albedo: Color,
+    medium: Arc<InteriorMedium>,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Color, mean_free_path: f64) -> Material {
+        Material::Subsurface(Subsurface {
+            albedo,
+            medium: Arc::new(InteriorMedium::subsurface(albedo, mean_free_path)),
+        })
+    }
+
+    pub fn medium(&self) -> &InteriorMedium {
+        &self.medium
+    }
+
+    pub fn same_as(&self, other: &Subsurface) -> bool {
+        Arc::ptr_eq(&self.medium, &other.medium)
+    }
+}
+
+impl MatFn for Subsurface {
+    // rec.normal faces the incoming ray, so light always goes through to the other side
+    fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        Some(ScatterRecord {
+            attenuation: Color::new(1., 1., 1.),
+            data: SrecData::PdfPtr(Box::new(CosinePDF::new(&-rec.normal))),
+        })
+    }
+
+    fn scattering_pdf(&self, _r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cos_theta = dot(&-rec.normal, &unit_vector(&scattered.direction()));
+        cos_theta.max(0.) / PI
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        let f = self.scattering_pdf(r_in, rec, scattered);
+        Color::new(f, f, f)
+    }
+}
+
+// A medium that glows as well as scattering, like fire or hot gas. Every collision with the medium
+// adds emit, so where the gas is too thick to see through it looks like a surface of radiance emit
+// and thinner parts glow less.
+#[derive(Clone)]
+pub struct EmissiveVolume {
+    phase_function: Box<Material>,
+    emit: Arc<Texture>,
+}
+
+impl EmissiveVolume {
+    pub fn new(phase_function: Material, emit: Arc<Texture>) -> Material {
+        Material::EmissiveVolume(EmissiveVolume {
+            phase_function: Box::new(phase_function),
+            emit,
+        })
+    }
+}
+
+impl MatFn for EmissiveVolume {
+    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<ScatterRecord> {
+        self.phase_function.scatter(r_in, rec)
+    }
+
+    fn emitted(&self, _r_in: &Ray, _rec: &HitRecord, u: f64, v: f64, p: &Point3) -> Color {
+        self.emit.value(u, v, p)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.phase_function.scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        self.phase_function.eval(r_in, rec, scattered)
+    }
 }
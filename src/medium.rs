@@ -0,0 +1,261 @@
+use crate::{
+    color::Color,
+    hittable::HitRecord,
+    integrator::Scene,
+    material::{Anisotropic, Isotropic, MatFn, Material, ScatterRecord, SrecData},
+    phase::PhaseFunction,
+    ray::Ray,
+    utils::{random_double, INF},
+    vec3::{dot, Vec3},
+};
+
+// What fills the inside of a dielectric. Every unit of distance travelled inside absorbs sigma_a
+// of each channel (Beer-Lambert) and scatters with probability sigma_s.
+#[derive(Clone)]
+pub struct InteriorMedium {
+    sigma_a: Color,
+    sigma_s: f64,
+    phase_function: Material,
+}
+
+impl InteriorMedium {
+    pub fn new(sigma_a: Color, sigma_s: f64, albedo: Color, phase: PhaseFunction) -> InteriorMedium {
+        let phase_function = match phase {
+            PhaseFunction::Isotropic => Isotropic::new(albedo),
+            _ => Anisotropic::new(albedo, phase),
+        };
+
+        InteriorMedium {
+            sigma_a,
+            sigma_s,
+            phase_function,
+        }
+    }
+
+    // Clear coloured glass or liquid that absorbs without scattering
+    pub fn absorbing(sigma_a: Color) -> InteriorMedium {
+        Self::new(sigma_a, 0., Color::new(1., 1., 1.), PhaseFunction::Isotropic)
+    }
+
+    // An absorbing medium that turns white light into color after distance
+    pub fn tinted(color: Color, distance: f64) -> InteriorMedium {
+        let sigma_a = |c: f64| -c.max(1e-6).ln() / distance;
+        Self::absorbing(Color::new(
+            sigma_a(color.x()),
+            sigma_a(color.y()),
+            sigma_a(color.z()),
+        ))
+    }
+
+    // The inside of translucent materials like wax, marble, milk and skin. Light travels about
+    // mean_free_path between scattering events, and after scattering around long enough the
+    // surface takes on the color albedo. Paths need a high max_depth to get back out.
+    pub fn subsurface(albedo: Color, mean_free_path: f64) -> InteriorMedium {
+        // Inverts the multiple scattering albedo to the albedo of a single event, from Chiang et al.
+        // "Practical and Controllable Subsurface Scattering for Production Path Tracing"
+        let single = |a: f64| {
+            let a = a.clamp(0., 1.);
+            let x = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            1. - x * x
+        };
+
+        Self::new(
+            Color::new_zero(),
+            1. / mean_free_path,
+            Color::new(single(albedo.x()), single(albedo.y()), single(albedo.z())),
+            PhaseFunction::Isotropic,
+        )
+    }
+
+    // Fraction of each channel left after travelling distance through the medium
+    pub fn transmittance(&self, distance: f64) -> Color {
+        beer_lambert(&self.sigma_a, distance)
+    }
+
+    // Shadow rays can't scatter back into the path, so light scattered out of them is lost as well
+    fn shadow_transmittance(&self, distance: f64) -> Color {
+        let sigma_s = Color::new(self.sigma_s, self.sigma_s, self.sigma_s);
+        beer_lambert(&(self.sigma_a + sigma_s), distance)
+    }
+}
+
+// Fraction of each channel left after distance with extinction sigma
+fn beer_lambert(sigma: &Color, distance: f64) -> Color {
+    let channel = |sigma: f64| {
+        if sigma == 0. {
+            1.
+        } else {
+            (-sigma * distance).exp()
+        }
+    };
+
+    Color::new(channel(sigma.x()), channel(sigma.y()), channel(sigma.z()))
+}
+
+// The closed objects a path is inside, innermost last: dielectrics and subsurface materials.
+// Where they overlap the one with the highest priority fills the space, and the boundaries of the
+// others inside it are ignored.
+#[derive(Clone, Default)]
+pub struct PathMedium<'a> {
+    stack: Vec<&'a Material>,
+}
+
+impl<'a> PathMedium<'a> {
+    // The material filling the space the path is in, None for air. Later entries win ties.
+    fn top(&self) -> Option<&'a Material> {
+        self.stack.iter().copied().max_by_key(|m| priority(m))
+    }
+
+    fn current(&self) -> Option<&'a InteriorMedium> {
+        self.top().and_then(interior)
+    }
+
+    // The next thing r meets: the surface scene.hit finds, or the point where the current medium
+    // scatters it before it gets there. Also returns the transmittance of the medium up to that point.
+    pub fn hit(&self, scene: &'a Scene, r: &Ray) -> (Option<HitRecord<'a>>, Color) {
+        let rec = scene.hit(r);
+
+        let medium = match self.current() {
+            Some(medium) => medium,
+            None => return (rec, Color::new(1., 1., 1.)),
+        };
+
+        let length = r.direction().length();
+        let distance = rec.as_ref().map_or(INF, |rec| rec.t * length);
+
+        if medium.sigma_s > 0. {
+            let scatter_distance = -(1. - random_double()).ln() / medium.sigma_s;
+
+            if scatter_distance < distance {
+                let t = scatter_distance / length;
+                let scatter = HitRecord {
+                    t,
+                    p: r.at(t),
+                    normal: Vec3::new(1., 0., 0.), // arbitrary
+                    front_face: true,              // arbitrary
+                    mat: &medium.phase_function,
+                    u: 0.,
+                    v: 0.,
+                };
+
+                return (Some(scatter), medium.transmittance(scatter_distance));
+            }
+        }
+
+        (rec, medium.transmittance(distance))
+    }
+
+    // Fraction of the light from distance along a shadow ray that gets through the medium the
+    // path is in
+    pub fn shadow_transmittance(&self, distance: f64) -> Color {
+        self.current()
+            .map_or(Color::new(1., 1., 1.), |m| m.shadow_transmittance(distance))
+    }
+
+    // rec.mat.scatter, except that dielectrics and Principled glass refract against whatever fills
+    // the space on the other side of the boundary instead of air, and boundaries hidden inside a
+    // higher priority material are passed straight through
+    pub fn scatter(&self, r: &Ray, rec: &HitRecord<'a>) -> Option<ScatterRecord> {
+        if self.hidden(rec) {
+            return Some(ScatterRecord {
+                attenuation: Color::new(1., 1., 1.),
+                data: SrecData::SkipRay(Ray::new_timed(rec.p, r.direction(), r.time())),
+            });
+        }
+
+        match rec.mat {
+            Material::Dielectric(d) => d.scatter_between(r, rec, self.outside_ir(r, rec)),
+            Material::Principled(p) => p.scatter_between(r, rec, self.outside_ir(r, rec)),
+            _ => rec.mat.scatter(r, rec),
+        }
+    }
+
+    // rec.mat.scattering_pdf against what is on the other side, see scatter
+    pub fn scattering_pdf(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        match rec.mat {
+            _ if self.hidden(rec) => 0.,
+            Material::Dielectric(d) => {
+                d.scattering_pdf_between(r, rec, scattered, self.outside_ir(r, rec))
+            }
+            _ => rec.mat.scattering_pdf(r, rec, scattered),
+        }
+    }
+
+    // rec.mat.eval against what is on the other side, see scatter
+    pub fn eval(&self, r: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        match rec.mat {
+            _ if self.hidden(rec) => Color::new_zero(),
+            Material::Dielectric(d) => d.eval_between(r, rec, scattered, self.outside_ir(r, rec)),
+            _ => rec.mat.eval(r, rec, scattered),
+        }
+    }
+
+    // The highest priority boundary the path is inside apart from rec.mat itself
+    fn outside(&self, rec: &HitRecord) -> Option<&'a Material> {
+        let mut others = self.stack.clone();
+        if let Some(i) = others.iter().rposition(|o| same(o, rec.mat)) {
+            others.remove(i);
+        }
+        others.into_iter().max_by_key(|o| priority(o))
+    }
+
+    // Whether rec is on a boundary inside a higher priority material, which doesn't exist there
+    fn hidden(&self, rec: &HitRecord) -> bool {
+        is_boundary(rec.mat)
+            && self
+                .outside(rec)
+                .is_some_and(|o| priority(o) > priority(rec.mat))
+    }
+
+    fn outside_ir(&self, r: &Ray, rec: &HitRecord) -> f64 {
+        match self.outside(rec) {
+            Some(Material::Dielectric(o)) => o.ir_at(r.wavelength()),
+            _ => 1.,
+        }
+    }
+
+    // Follows the path from rec into scattered. Going through a boundary pushes it onto the
+    // stack when entering and pops it when leaving.
+    pub fn cross(&mut self, rec: &HitRecord<'a>, scattered: &Ray) {
+        // Reflections stay on the same side
+        if !is_boundary(rec.mat) || dot(&scattered.direction(), &rec.normal) > 0. {
+            return;
+        }
+
+        if rec.front_face {
+            self.stack.push(rec.mat);
+        } else if let Some(i) = self.stack.iter().rposition(|o| same(o, rec.mat)) {
+            self.stack.remove(i);
+        }
+    }
+}
+
+// Materials that enclose the space inside the objects they are on
+fn is_boundary(m: &Material) -> bool {
+    matches!(m, Material::Dielectric(_) | Material::Subsurface(_))
+}
+
+fn priority(m: &Material) -> u32 {
+    match m {
+        Material::Dielectric(d) => d.priority(),
+        _ => 0,
+    }
+}
+
+fn interior(m: &Material) -> Option<&InteriorMedium> {
+    match m {
+        Material::Dielectric(d) => d.medium(),
+        Material::Subsurface(s) => Some(s.medium()),
+        _ => None,
+    }
+}
+
+// Whether a and b are the same material, so leaving through one face of an object made of
+// several primitives matches entering through another
+fn same(a: &Material, b: &Material) -> bool {
+    match (a, b) {
+        (Material::Dielectric(a), Material::Dielectric(b)) => a.same_as(b),
+        (Material::Subsurface(a), Material::Subsurface(b)) => a.same_as(b),
+        _ => false,
+    }
+}
@@ -0,0 +1,208 @@
+use std::f64::consts::PI;
+
+use crate::{
+    color::Color,
+    onb::Onb,
+    utils::random_double,
+    vec3::{cross, dot, unit_vector, Vec3},
+};
+
+// The GGX (Trowbridge-Reitz) distribution of microfacet normals. Directions are in the local
+// frame of the surface, with the normal along z.
+#[derive(Clone, Copy)]
+pub struct TrowbridgeReitz {
+    alpha: f64,
+}
+
+impl TrowbridgeReitz {
+    // roughness from 0 for a mirror to 1, squared so that it looks about linear
+    pub fn new(roughness: f64) -> TrowbridgeReitz {
+        let roughness = roughness.clamp(0., 1.);
+        TrowbridgeReitz {
+            alpha: (roughness * roughness).max(1e-4),
+        }
+    }
+
+    // Rough enough to be worth sampling, anything smoother is treated as a perfect mirror
+    pub fn is_smooth(&self) -> bool {
+        self.alpha < 1e-3
+    }
+
+    // Density of microfacets with normal wm
+    pub fn d(&self, wm: &Vec3) -> f64 {
+        let cos2 = wm.z() * wm.z();
+        if cos2 <= 0. {
+            return 0.;
+        }
+
+        let tan2 = (1. - cos2) / cos2;
+        let a2 = self.alpha * self.alpha;
+        let e = 1. + tan2 / a2;
+        1. / (PI * a2 * cos2 * cos2 * e * e)
+    }
+
+    fn lambda(&self, w: &Vec3) -> f64 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0. {
+            return INFINITE_LAMBDA;
+        }
+
+        let tan2 = (1. - cos2) / cos2;
+        ((1. + self.alpha * self.alpha * tan2).sqrt() - 1.) / 2.
+    }
+
+    // Smith masking, the fraction of microfacets facing w that w can see
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        1. / (1. + self.lambda(w))
+    }
+
+    // Height correlated Smith masking and shadowing for light going between wo and wi
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the microfacet normals seen from w, which is what sample_wm picks from
+    pub fn pdf(&self, w: &Vec3, wm: &Vec3) -> f64 {
+        let cos = w.z().abs();
+        if cos <= 0. {
+            return 0.;
+        }
+
+        self.g1(w) / cos * self.d(wm) * dot(w, wm).max(0.)
+    }
+
+    // A microfacet normal visible from w, following Heitz "Sampling the GGX Distribution of
+    // Visible Normals"
+    pub fn sample_wm(&self, w: &Vec3) -> Vec3 {
+        let w = if w.z() < 0. { -*w } else { *w };
+
+        // Stretch to the hemisphere of a distribution with alpha 1
+        let wh = unit_vector(&Vec3::new(self.alpha * w.x(), self.alpha * w.y(), w.z()));
+
+        let len2 = wh.x() * wh.x() + wh.y() * wh.y();
+        let t1 = if len2 > 0. {
+            Vec3::new(-wh.y(), wh.x(), 0.) / len2.sqrt()
+        } else {
+            Vec3::new(1., 0., 0.)
+        };
+        let t2 = cross(&wh, &t1);
+
+        // A point on the disk, squashed onto the part of the hemisphere wh can see
+        let r = random_double().sqrt();
+        let phi = 2. * PI * random_double();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1. + wh.z());
+        let p2 = (1. - s) * (1. - p1 * p1).sqrt() + s * r * phi.sin();
+
+        let nh = p1 * t1 + p2 * t2 + (1. - p1 * p1 - p2 * p2).max(0.).sqrt() * wh;
+
+        // And back to the real alpha
+        unit_vector(&Vec3::new(
+            self.alpha * nh.x(),
+            self.alpha * nh.y(),
+            nh.z().max(1e-6),
+        ))
+    }
+}
+
+const INFINITE_LAMBDA: f64 = 1e12;
+
+// The frame a microfacet BRDF works in, with w along the shading normal
+pub fn to_local(uvw: &Onb, v: &Vec3) -> Vec3 {
+    Vec3::new(dot(v, &uvw.u()), dot(v, &uvw.v()), dot(v, &uvw.w()))
+}
+
+// How much light a dielectric reflects at an angle with cosine cos_theta, where eta is the index
+// the light comes from over the index it goes into: Schlick's approximation, and all of it past
+// the critical angle
+pub fn dielectric_reflectance(cos_theta: f64, eta: f64) -> f64 {
+    let cos_theta = cos_theta.min(1.);
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    if eta * sin_theta > 1. {
+        return 1.;
+    }
+
+    // Matching indices make an invisible boundary, which Schlick still reflects at grazing angles
+    if eta == 1. {
+        return 0.;
+    }
+
+    let r0 = (1. - eta) / (1. + eta);
+    let r0 = r0 * r0;
+
+    r0 + (1. - r0) * (1. - cos_theta).powf(5.)
+}
+
+// The microfacet normal that sends wo to wi on a dielectric where eta is the index on wo's side
+// over the index on the other, facing up: the half vector for reflections and the generalised
+// half vector of Walter et al. for refractions. None when it would face away from either.
+pub fn half_vector(wo: &Vec3, wi: &Vec3, eta: f64) -> Option<Vec3> {
+    if wo.z() == 0. || wi.z() == 0. {
+        return None;
+    }
+
+    let wm = if wo.z() * wi.z() > 0. {
+        *wo + *wi
+    } else {
+        eta * *wo + *wi
+    };
+    if wm.length_squared() == 0. {
+        return None;
+    }
+
+    let wm = unit_vector(&wm);
+    let wm = if wm.z() < 0. { -wm } else { wm };
+    if dot(&wm, wi) * wi.z() < 0. || dot(&wm, wo) * wo.z() < 0. {
+        return None;
+    }
+
+    Some(wm)
+}
+
+// How much light a metal reflects at an angle with cosine cos_theta
+#[derive(Clone, Copy)]
+pub enum ConductorFresnel {
+    // Schlick's approximation, from the color straight on to white at grazing angles
+    Schlick(Color),
+    // The exact Fresnel equations for a conductor with complex index of refraction eta + ik
+    Complex { eta: Color, k: Color },
+}
+
+impl ConductorFresnel {
+    pub fn value(&self, cos_theta: f64) -> Color {
+        let cos_theta = cos_theta.clamp(0., 1.);
+
+        match *self {
+            ConductorFresnel::Schlick(f0) => {
+                let m = (1. - cos_theta).powi(5);
+                f0 + (Color::new(1., 1., 1.) - f0) * m
+            }
+            ConductorFresnel::Complex { eta, k } => Color::new(
+                fresnel_complex(cos_theta, eta.x(), k.x()),
+                fresnel_complex(cos_theta, eta.y(), k.y()),
+                fresnel_complex(cos_theta, eta.z(), k.z()),
+            ),
+        }
+    }
+}
+
+// Unpolarized reflectance of a conductor, from pbrt's FrConductor
+fn fresnel_complex(cos_theta: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta * cos_theta;
+    let sin2 = 1. - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4. * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.).sqrt();
+    let t2 = 2. * cos_theta * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
@@ -0,0 +1,314 @@
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    color::{luminance, Color},
+    integrator::{Integrate, Integrator, Scene},
+    ray::Ray,
+    render::get_ray_raster,
+    sampler::{set_sample_stream, SampleStream},
+    utils::random_double,
+};
+
+// Primary sample space Metropolis light transport (Kelemen et al.). A path is a function of the
+// uniform numbers it draws, so chains mutate those numbers around bright paths and splat every
+// state into the image, which finds narrow light paths that independent samples keep missing.
+#[derive(Clone)]
+pub struct Mlt {
+    inner: Box<Integrator>,
+    bootstrap: usize,
+    chains: usize,
+    sigma: f64,
+    large_step_probability: f64,
+}
+
+impl Mlt {
+    // Runs chains Markov chains over the paths of inner, normalized with bootstrap independent paths.
+    // sigma is the size of a small mutation and large_step_probability how often a chain jumps to a
+    // completely new path.
+    pub fn new(
+        inner: Integrator,
+        bootstrap: usize,
+        chains: usize,
+        sigma: f64,
+        large_step_probability: f64,
+    ) -> Integrator {
+        Integrator::Mlt(Mlt {
+            inner: Box::new(inner),
+            bootstrap,
+            chains,
+            sigma,
+            large_step_probability,
+        })
+    }
+
+    fn sampler(&self, seed: u64) -> Rc<RefCell<MltSampler>> {
+        Rc::new(RefCell::new(MltSampler::new(
+            seed,
+            self.sigma,
+            self.large_step_probability,
+        )))
+    }
+
+    // The path for the primary samples of the running chain: the pixel it lands in and its radiance
+    fn path(&self, scene: &Scene, width: usize, height: usize) -> (usize, Color) {
+        let x = random_double() * width as f64;
+        let y = random_double() * height as f64;
+
+        let r = get_ray_raster(scene.cam, x, y);
+        let radiance = self.inner.ray_color(&r, scene);
+
+        let i = (x as usize).min(width - 1);
+        let j = (y as usize).min(height - 1);
+        (j * width + i, radiance)
+    }
+
+    fn bootstrap_weight(&self, seed: u64, scene: &Scene, width: usize, height: usize) -> f64 {
+        set_sample_stream(Some(self.sampler(seed)));
+        let (_, radiance) = self.path(scene, width, height);
+        set_sample_stream(None);
+
+        contribution(&radiance)
+    }
+
+    fn run_chain(&self, chain: Chain, scene: &Scene, width: usize, height: usize) {
+        let Chain { seed, mutations, mut rng, film } = chain;
+
+        // Recreating the bootstrap sampler replays the bootstrap path as the starting state
+        let sampler = self.sampler(seed);
+        set_sample_stream(Some(sampler.clone()));
+
+        let (mut current_pixel, mut current_radiance) = self.path(scene, width, height);
+        let mut current_f = contribution(&current_radiance);
+
+        for _ in 0..mutations {
+            sampler.borrow_mut().start_iteration();
+            let (pixel, radiance) = self.path(scene, width, height);
+            let f = contribution(&radiance);
+
+            let accept = if current_f > 0. {
+                (f / current_f).min(1.)
+            } else {
+                1.
+            };
+
+            // Splat both states weighted by how likely each one is to be the next state
+            if f > 0. {
+                film[pixel] = film[pixel] + radiance * (accept / f);
+            }
+            if current_f > 0. {
+                film[current_pixel] =
+                    film[current_pixel] + current_radiance * ((1. - accept) / current_f);
+            }
+
+            if rng.gen::<f64>() < accept {
+                current_pixel = pixel;
+                current_radiance = radiance;
+                current_f = f;
+                sampler.borrow_mut().accept();
+            } else {
+                sampler.borrow_mut().reject();
+            }
+        }
+
+        set_sample_stream(None);
+    }
+}
+
+impl Integrate for Mlt {
+    fn prepare(&self, scene: &Scene) {
+        self.inner.prepare(scene);
+    }
+
+    // A single independent path of the inner integrator, the chains only run in render
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        self.inner.ray_color(r, scene)
+    }
+
+    fn render(&self, scene: &Scene, pixels: &mut [Color]) {
+        let width = scene.cam.image_width as usize;
+        let height = pixels.len() / width;
+
+        eprintln!("Bootstrapping with {} paths", self.bootstrap);
+        let weights: Vec<f64> = (0..self.bootstrap as u64)
+            .into_par_iter()
+            .map(|seed| self.bootstrap_weight(seed, scene, width, height))
+            .collect();
+
+        let cdf: Vec<f64> = weights
+            .iter()
+            .scan(0., |sum, w| {
+                *sum += w;
+                Some(*sum)
+            })
+            .collect();
+        let total = cdf.last().copied().unwrap_or(0.);
+        if total <= 0. {
+            eprintln!("No bootstrap path carried any light, nothing to render");
+            return;
+        }
+        // Average luminance of the image, which the chains only know up to a constant
+        let b = total / self.bootstrap as f64;
+
+        let total_mutations = scene.cam.samples_per_pixel as u64 * pixels.len() as u64;
+        let chains = self.chains.max(1) as u64;
+        let mutations = total_mutations / chains;
+
+        let counter = std::sync::Mutex::new(0);
+        let film = (0..chains)
+            .into_par_iter()
+            .fold(
+                || vec![Color::new_zero(); pixels.len()],
+                |mut film, chain| {
+                    // Chains start from bootstrap paths picked in proportion to their contribution
+                    let mut rng = StdRng::seed_from_u64(self.bootstrap as u64 + chain);
+                    let u = rng.gen::<f64>() * total;
+                    let seed = cdf.partition_point(|c| *c <= u).min(cdf.len() - 1) as u64;
+
+                    let state = Chain {
+                        seed,
+                        mutations,
+                        rng,
+                        film: &mut film,
+                    };
+                    self.run_chain(state, scene, width, height);
+
+                    let mut counter = counter.lock().expect("should work");
+                    *counter += 1;
+                    eprint!("\rProgress {:.1}%", *counter as f64 * 100. / chains as f64);
+                    film
+                },
+            )
+            .reduce(
+                || vec![Color::new_zero(); pixels.len()],
+                |a, b| a.iter().zip(b).map(|(a, b)| *a + b).collect(),
+            );
+
+        // write_color divides by samples_per_pixel, so scale the splats to the same total
+        let scale = b * total_mutations as f64 / (chains * mutations) as f64;
+        for (pixel, splat) in pixels.iter_mut().zip(film) {
+            *pixel = *pixel + splat * scale;
+        }
+    }
+}
+
+// One Markov chain: the bootstrap path it starts from, how many mutations it runs, the rng
+// deciding acceptance and the film its states are splatted into
+struct Chain<'a> {
+    seed: u64,
+    mutations: u64,
+    rng: StdRng,
+    film: &'a mut [Color],
+}
+
+// The scalar the chains are distributed by
+fn contribution(radiance: &Color) -> f64 {
+    let y = luminance(radiance);
+    if y.is_finite() {
+        y.max(0.)
+    } else {
+        0.
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    backup: f64,
+    backup_modified: u64,
+}
+
+// The primary sample vector of one chain. Samples are mutated lazily the first time a path
+// asks for them in an iteration, catching up on the small steps they missed in between.
+struct MltSampler {
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    fn new(seed: u64, sigma: f64, large_step_probability: f64) -> MltSampler {
+        MltSampler {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            samples: vec![],
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn mutate(&mut self, i: usize) {
+        if i >= self.samples.len() {
+            self.samples.resize(i + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[i];
+
+        // Untouched since the last accepted large step, which replaced every sample
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.backup = sample.value;
+        sample.backup_modified = sample.last_modified;
+
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // n small gaussian steps at once add up to one with sqrt(n) times the spread
+            let steps = (self.iteration - sample.last_modified) as f64;
+            let u1: f64 = self.rng.gen();
+            let u2: f64 = self.rng.gen();
+            let normal = (-2. * (1. - u1).ln()).sqrt() * (2. * PI * u2).cos();
+
+            sample.value += normal * self.sigma * steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+
+        sample.last_modified = self.iteration;
+    }
+}
+
+impl SampleStream for MltSampler {
+    fn next_sample(&mut self) -> f64 {
+        let i = self.index;
+        self.index += 1;
+        self.mutate(i);
+        self.samples[i].value
+    }
+}
@@ -2,16 +2,21 @@ use std::f64::consts::PI;
 use std::ops;
 use std::sync::Arc;
 
-use crate::color::Color;
+use crate::color::{luminance, Color};
 use crate::constant_medium::ConstantMedium;
+use crate::distribution::Distribution2D;
+use crate::heterogeneous_medium::HeterogeneousMedium;
 use crate::hittable::{BvhNode, HitRecord, Hittable, HittableList};
 use crate::interval::{self, Interval, EMPTY};
-use crate::material::Material;
+use crate::lights::{LightTree, PowerLights};
+use crate::material::{DiffuseLight, Material};
 use crate::onb::Onb;
+use crate::pdf::{AnyPDF, ConePDF};
+use crate::photometry::{chromaticity, LightUnit};
 use crate::ray::Ray;
 use crate::transform::Transform;
 use crate::utils::{self, random_double, INF};
-use crate::vec3::{cross, dot, unit_vector, Point3, Vec3};
+use crate::vec3::{cross, dot, random_unit_vector, unit_vector, Point3, Vec3};
 
 // Using Arc's for now, but figure out more efficient way to do it later
 #[derive(Clone)]
@@ -23,6 +28,9 @@ pub enum Object {
     Quad(Quad),
     Transform(Transform),
     Volume(ConstantMedium),
+    HeterogeneousVolume(HeterogeneousMedium),
+    PowerLights(Arc<PowerLights>),
+    LightTree(Arc<LightTree>),
 }
 
 impl Hittable for Object {
@@ -35,6 +43,9 @@ impl Hittable for Object {
             Object::_Plane(p) => p.hit(r, ray_t),
             Object::Transform(p) => p.hit(r, ray_t),
             Object::Volume(p) => p.hit(r, ray_t),
+            Object::HeterogeneousVolume(p) => p.hit(r, ray_t),
+            Object::PowerLights(p) => p.hit(r, ray_t),
+            Object::LightTree(p) => p.hit(r, ray_t),
         }
     }
 
@@ -47,6 +58,9 @@ impl Hittable for Object {
             Object::Quad(o) => o.bounding_box(),
             Object::Transform(o) => o.bounding_box(),
             Object::Volume(o) => o.bounding_box(),
+            Object::HeterogeneousVolume(o) => o.bounding_box(),
+            Object::PowerLights(o) => o.bounding_box(),
+            Object::LightTree(o) => o.bounding_box(),
         }
     }
 
@@ -55,6 +69,9 @@ impl Hittable for Object {
             Object::Quad(o) => o.random(origin),
             Object::Sphere(o) => o.random(origin),
             Object::List(o) => o.random(origin),
+            Object::Transform(o) => o.random(origin),
+            Object::PowerLights(o) => o.random(origin),
+            Object::LightTree(o) => o.random(origin),
             _ => Vec3::new(1., 0., 0.)
         }
     }
@@ -64,10 +81,96 @@ impl Hittable for Object {
             Object::Quad(o) => o.pdf_value(origin, direction),
             Object::Sphere(o) => o.pdf_value(origin, direction),
             Object::List(o) => o.pdf_value(origin, direction),
+            Object::Transform(o) => o.pdf_value(origin, direction),
+            Object::PowerLights(o) => o.pdf_value(origin, direction),
+            Object::LightTree(o) => o.pdf_value(origin, direction),
             _ => 0.
         }
     }
 
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        match self {
+            Object::Quad(o) => o.sample_surface(time),
+            Object::Sphere(o) => o.sample_surface(time),
+            Object::List(o) => o.sample_surface(time),
+            Object::Transform(o) => o.sample_surface(time),
+            Object::PowerLights(o) => o.sample_surface(time),
+            Object::LightTree(o) => o.sample_surface(time),
+            _ => None,
+        }
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        match self {
+            Object::Quad(o) => o.surface_pdf(origin, p),
+            Object::Sphere(o) => o.surface_pdf(origin, p),
+            Object::List(o) => o.surface_pdf(origin, p),
+            Object::Transform(o) => o.surface_pdf(origin, p),
+            Object::PowerLights(o) => o.surface_pdf(origin, p),
+            Object::LightTree(o) => o.surface_pdf(origin, p),
+            _ => 0.,
+        }
+    }
+}
+
+impl Object {
+    // Total power leaving an emitter, used to decide how often to sample it. Textured lights
+    // are judged by the color in the middle of their surface.
+    pub fn power(&self) -> f64 {
+        match self {
+            Object::Sphere(o) => {
+                let emission = o.mat.emission(0.5, 0.5, &o.center);
+                luminance(&emission) * PI * 4. * PI * o.radius * o.radius
+            }
+            Object::Quad(o) => {
+                let sides = if o.mat.is_two_sided() { 2. } else { 1. };
+                o.average_emission() * PI * o.area * sides
+            }
+            Object::List(o) => o.objects.iter().map(|object| object.power()).sum(),
+            Object::Node(o) => o.power(),
+            Object::Transform(o) => o.power(),
+            _ => 0.,
+        }
+    }
+
+    // Adds the names of the light groups used by emitters in this object, skipping ones already in groups
+    pub fn light_groups(&self, groups: &mut Vec<String>) {
+        let mat = match self {
+            Object::Sphere(o) => &o.mat,
+            Object::Quad(o) => &o.mat,
+            Object::List(o) => {
+                o.objects.iter().for_each(|object| object.light_groups(groups));
+                return;
+            }
+            Object::Node(o) => return o.light_groups(groups),
+            Object::Transform(o) => return o.light_groups(groups),
+            _ => return,
+        };
+
+        if let Some(group) = mat.light_group() {
+            if !groups.iter().any(|g| g == group) {
+                groups.push(group.to_string());
+            }
+        }
+    }
+
+    // Adds every emitting sphere and quad in this object to lights, keeping the transforms above them
+    pub fn collect_lights(&self, lights: &mut HittableList) {
+        match self {
+            Object::Sphere(o) if o.mat.is_emitter() => lights.add(self.clone()),
+            Object::Quad(o) if o.mat.is_emitter() => {
+                lights.add(self.clone())
+            }
+            Object::List(o) => {
+                for object in o.objects.iter() {
+                    object.collect_lights(lights);
+                }
+            }
+            Object::Node(o) => o.collect_lights(lights),
+            Object::Transform(o) => o.collect_lights(lights),
+            _ => (),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -80,7 +183,8 @@ pub struct Sphere {
 }
 
 impl Sphere {
-    pub fn new(center: Point3, radius: f64, mat: Material) -> Object {
+    pub fn new(center: Point3, radius: f64, mat: impl Into<Material>) -> Object {
+        let mat = mat.into();
         let rvec = Vec3::new(radius, radius, radius);
         Object::Sphere(Sphere {
             center,
@@ -91,7 +195,20 @@ impl Sphere {
         })
     }
 
-    pub fn new_moving(center1: Point3, center2: Point3, radius: f64, mat: Material) -> Object {
+    // A glowing ball of color (see photometry::chromaticity) as bright as unit. Seen from any
+    // direction it shows an area of pi r^2, and it sends its flux over the whole sphere of directions.
+    pub fn light(center: Point3, radius: f64, color: Color, unit: LightUnit) -> Object {
+        let radiance = unit.candela(4. * PI) / (PI * radius * radius);
+        Sphere::new(center, radius, DiffuseLight::new(chromaticity(&color) * radiance))
+    }
+
+    pub fn new_moving(
+        center1: Point3,
+        center2: Point3,
+        radius: f64,
+        mat: impl Into<Material>,
+    ) -> Object {
+        let mat = mat.into();
         let rvec = Vec3::new(radius, radius, radius);
         let box1 = Aabb::from_points(&(center1 - rvec), &(center1 + rvec));
         let box2 = Aabb::from_points(&(center2 - rvec), &(center2 + rvec));
@@ -193,7 +310,12 @@ impl Hittable for Sphere {
         match self.hit(&Ray::new(*origin, *direction), &Interval { min: 0.001, max: INF }) {
             None => 0.,
             Some(_) => {
-                let cos_theta_max = (1. - self.radius*self.radius / (self.center - *origin).length_squared()).sqrt();
+                let distance_squared = (self.center - *origin).length_squared();
+                if distance_squared <= self.radius * self.radius {
+                    return 1. / (4. * PI);
+                }
+
+                let cos_theta_max = (1. - self.radius*self.radius / distance_squared).sqrt();
                 let solid_angle = 2. * PI * (1. - cos_theta_max);
 
                 1. / solid_angle
@@ -204,36 +326,88 @@ impl Hittable for Sphere {
     fn random(&self, origin: &Point3) -> Vec3 {
         let direction = self.center - *origin;
         let distance_squared = direction.length_squared();
+
+        // From inside, or on the surface of emitters that also scatter, the sphere is everywhere
+        if distance_squared <= self.radius * self.radius {
+            return random_unit_vector();
+        }
+
         let mut uvw = Onb::default();
         uvw.build_from_w(&direction);
 
         uvw.local_vec(&Self::random_to_sphere(self.radius, distance_squared))
 
     }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        let outward_normal = random_unit_vector();
+        let (u, v) = Sphere::get_sphere_uv(&outward_normal);
+
+        let rec = HitRecord {
+            t: 0.,
+            p: self.center(time) + self.radius * outward_normal,
+            mat: &self.mat,
+            u,
+            v,
+            normal: outward_normal,
+            front_face: true,
+        };
+
+        Some((rec, 1. / (4. * PI * self.radius * self.radius)))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        match self.hit(&Ray::new(*origin, *p - *origin), &Interval { min: 0.001, max: INF }) {
+            Some(rec) if (rec.t - 1.).abs() < 1e-4 => 1. / (4. * PI * self.radius * self.radius),
+            _ => 0.,
+        }
+    }
 }
 
-// A disk light source infinitely far away
+// A disk light source infinitely far away. irradiance is the light falling on a surface facing
+// it, which the disc spreads over its angular_diameter in degrees (about 0.53 for the real sun).
+#[derive(Clone)]
 pub struct Sun {
     pub direction: Vec3,
-    _albedo: Color,
-    _limit: f64,
+    radiance: Color,
+    limit: f64, // cosine of the angular radius
 }
 
 impl Sun {
-    pub fn new(direction: Vec3, albedo: Color, angular_diameter: f64) -> Sun {
-        let limit = 1. - angular_diameter / 180.;
+    pub fn new(direction: Vec3, irradiance: Color, angular_diameter: f64) -> Sun {
+        let limit = (angular_diameter / 2.).to_radians().cos();
+        let solid_angle = 2. * PI * (1. - limit);
 
         Sun {
             direction: unit_vector(&direction),
-            _albedo: albedo,
-            _limit: limit,
+            radiance: irradiance / solid_angle,
+            limit,
         }
     }
 
-    pub fn _hit(&self, r: &Ray) -> Color {
+    // Elevation is measured up from the horizon and azimuth clockwise from -z towards +x, in degrees
+    pub fn from_angles(elevation: f64, azimuth: f64, irradiance: Color, angular_diameter: f64) -> Sun {
+        let elevation = elevation.to_radians();
+        let azimuth = azimuth.to_radians();
+
+        let direction = Vec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+
+        Sun::new(direction, irradiance, angular_diameter)
+    }
+
+    // Directions towards the disc, for sampling it directly
+    pub fn pdf(&self) -> AnyPDF {
+        ConePDF::new(&self.direction, self.limit)
+    }
+
+    pub fn hit(&self, r: &Ray) -> Color {
         let unit_direction = unit_vector(&r.direction());
-        if dot(&unit_direction, &self.direction) > self._limit {
-            self._albedo
+        if dot(&unit_direction, &self.direction) > self.limit {
+            self.radiance
         } else {
             Color::new_zero()
         }
@@ -337,6 +511,22 @@ impl Aabb {
         }
     }
 
+    pub fn centroid(&self) -> Point3 {
+        Point3::new(
+            0.5 * (self.x.min + self.x.max),
+            0.5 * (self.y.min + self.y.max),
+            0.5 * (self.z.min + self.z.max),
+        )
+    }
+
+    pub fn diagonal(&self) -> Vec3 {
+        Vec3::new(self.x.size(), self.y.size(), self.z.size())
+    }
+
+    pub fn contains(&self, p: &Point3) -> bool {
+        self.x.contains(p.x()) && self.y.contains(p.y()) && self.z.contains(p.z())
+    }
+
     pub fn hit(&self, r: &Ray, ray_t: &Interval) -> bool {
         // Override and create copy to get around mutability rules
         let mut ray_t = Interval {
@@ -422,15 +612,32 @@ pub struct Quad {
     d: f64,
     w: Vec3,
     area: f64,
+    emission: Option<Arc<Distribution2D>>, // luminance of a textured light over (u, v)
 }
 
+// Resolution of the grid textured lights are importance sampled with
+const EMISSION_GRID: usize = 128;
+
 impl Quad {
-    pub fn new(q: Point3, u: Vec3, v: Vec3, mat: Material) -> Object {
+    pub fn new(q: Point3, u: Vec3, v: Vec3, mat: impl Into<Material>) -> Object {
+        let mat = mat.into();
         let bbox = Aabb::from_points(&q, &(q + u + v)).pad();
         let n = cross(&u, &v);
         let normal = unit_vector(&n);
         let w = n / dot(&n, &n);
 
+        let emission = mat.emission_texture().map(|texture| {
+            let mut func = Vec::with_capacity(EMISSION_GRID * EMISSION_GRID);
+            for j in 0..EMISSION_GRID {
+                for i in 0..EMISSION_GRID {
+                    let a = (i as f64 + 0.5) / EMISSION_GRID as f64;
+                    let b = (j as f64 + 0.5) / EMISSION_GRID as f64;
+                    func.push(luminance(&texture.value(a, b, &(q + a * u + b * v))));
+                }
+            }
+            Arc::new(Distribution2D::new(&func, EMISSION_GRID, EMISSION_GRID))
+        });
+
         Object::Quad(Quad {
             q,
             u,
@@ -441,8 +648,58 @@ impl Quad {
             d: dot(&normal, &q),
             w,
             area: n.length(),
+            emission,
         })
     }
+
+    // A panel of color (see photometry::chromaticity) emitting from its front, or both faces when
+    // two_sided, as bright as unit however big it is. Lambertian panels are brightest along their
+    // normal and send out pi times that intensity from each side, so the flux is split between them.
+    pub fn light(
+        q: Point3,
+        u: Vec3,
+        v: Vec3,
+        color: Color,
+        unit: LightUnit,
+        two_sided: bool,
+    ) -> Object {
+        let area = cross(&u, &v).length();
+        let sides = if two_sided { 2. } else { 1. };
+        let radiance = unit.candela(PI * sides) / area;
+
+        let light = DiffuseLight::new(chromaticity(&color) * radiance);
+        match two_sided {
+            true => Quad::new(q, u, v, light.two_sided()),
+            false => Quad::new(q, u, v, light),
+        }
+    }
+
+    // A point in plane coordinates to sample and its pdf per unit area, uniform unless the quad
+    // is a textured light
+    fn sample_point(&self) -> (f64, f64, f64) {
+        match &self.emission {
+            Some(emission) => {
+                let ((a, b), pdf) = emission.sample_continuous(random_double(), random_double());
+                (a, b, pdf / self.area)
+            }
+            None => (random_double(), random_double(), 1. / self.area),
+        }
+    }
+
+    fn point_pdf(&self, a: f64, b: f64) -> f64 {
+        match &self.emission {
+            Some(emission) => emission.pdf(a, b) / self.area,
+            None => 1. / self.area,
+        }
+    }
+
+    // Mean luminance emitted over the quad
+    fn average_emission(&self) -> f64 {
+        match &self.emission {
+            Some(emission) => emission.average(),
+            None => luminance(&self.mat.emission(0.5, 0.5, &(self.q + 0.5 * self.u + 0.5 * self.v))),
+        }
+    }
 }
 
 impl Hittable for Quad {
@@ -495,15 +752,39 @@ impl Hittable for Quad {
             Some(rec) => {
                 let distance_squared = rec.t * rec.t * direction.length_squared();
                 let cosine = (dot(&direction, &rec.normal) / direction.length()).abs();
-                distance_squared / (cosine * self.area)
+                self.point_pdf(rec.u, rec.v) * distance_squared / cosine
             }
         }
     }
 
     fn random(&self, origin: &Point3) -> Vec3 {
-        let p = self.q + (random_double() * self.u) + (random_double() * self.v);
+        let (a, b, _) = self.sample_point();
+        let p = self.q + (a * self.u) + (b * self.v);
         p - *origin
     }
+
+    fn sample_surface(&self, _time: f64) -> Option<(HitRecord, f64)> {
+        let (a, b, pdf) = self.sample_point();
+
+        let rec = HitRecord {
+            t: 0.,
+            p: self.q + (a * self.u) + (b * self.v),
+            mat: &self.mat,
+            front_face: true,
+            normal: self.normal,
+            u: a,
+            v: b,
+        };
+
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        match self.hit(&Ray::new(*origin, *p - *origin), &Interval { min: 0.001, max: utils::INF }) {
+            Some(rec) if (rec.t - 1.).abs() < 1e-4 => self.point_pdf(rec.u, rec.v),
+            _ => 0.,
+        }
+    }
 }
 
 pub fn make_box(a: &Point3, b: &Point3, mat: &Material) -> Object {
@@ -2,14 +2,21 @@ use std::f64::consts::PI;
 use std::sync::Arc;
 
 use crate::{
-    hittable::Hittable, object::Object, onb::Onb, utils::random_double, vec3::{dot, random_cosine_direction, random_unit_vector, unit_vector, Point3, Vec3}
+    microfacet::{dielectric_reflectance, half_vector, to_local, TrowbridgeReitz},
+    phase::PhaseFunction,
+    hittable::Hittable, object::Object, onb::Onb, utils::random_double, vec3::{dot, random_cosine_direction, random_unit_vector, refract, unit_vector, Point3, Vec3}
 };
 
 pub enum AnyPDF {
     Sphere(SpherePDF),
     Cosine(CosinePDF),
-    _Mixed(MixturePDF),
+    Mixed(MixturePDF),
     Hittable(HittablePDF),
+    Phase(PhasePDF),
+    Microfacet(MicrofacetPDF),
+    Cone(ConePDF),
+    Fuzz(FuzzPDF),
+    Dielectric(DielectricPDF),
 }
 
 impl PDF for AnyPDF {
@@ -18,8 +25,13 @@ impl PDF for AnyPDF {
         match self {
             AnyPDF::Sphere(p) => p.value(direction),
             AnyPDF::Cosine(p) => p.value(direction),
-            AnyPDF::_Mixed(p) => p.value(direction),
+            AnyPDF::Mixed(p) => p.value(direction),
             AnyPDF::Hittable(p) => p.value(direction),
+            AnyPDF::Phase(p) => p.value(direction),
+            AnyPDF::Microfacet(p) => p.value(direction),
+            AnyPDF::Cone(p) => p.value(direction),
+            AnyPDF::Fuzz(p) => p.value(direction),
+            AnyPDF::Dielectric(p) => p.value(direction),
 
         }
     }
@@ -28,8 +40,13 @@ impl PDF for AnyPDF {
         match self {
             AnyPDF::Sphere(p) => p.generate(),
             AnyPDF::Cosine(p) => p.generate(),
-            AnyPDF::_Mixed(p) => p.generate(),
+            AnyPDF::Mixed(p) => p.generate(),
             AnyPDF::Hittable(p) => p.generate(),
+            AnyPDF::Phase(p) => p.generate(),
+            AnyPDF::Microfacet(p) => p.generate(),
+            AnyPDF::Cone(p) => p.generate(),
+            AnyPDF::Fuzz(p) => p.generate(),
+            AnyPDF::Dielectric(p) => p.generate(),
         }
 
     }
@@ -77,6 +94,219 @@ impl PDF for CosinePDF {
     }
 }
 
+// Uniform directions within a cone around w, such as towards a distant disc like the sun
+pub struct ConePDF {
+    uvw: Onb,
+    cos_theta_max: f64,
+}
+
+impl ConePDF {
+    pub fn new(w: &Vec3, cos_theta_max: f64) -> AnyPDF {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(w);
+        AnyPDF::Cone(ConePDF { uvw, cos_theta_max })
+    }
+}
+
+impl PDF for ConePDF {
+    fn value(&self, direction: &Vec3) -> f64 {
+        if dot(&unit_vector(direction), &self.uvw.w()) <= self.cos_theta_max {
+            return 0.;
+        }
+
+        1. / (2. * PI * (1. - self.cos_theta_max))
+    }
+
+    fn generate(&self) -> Vec3 {
+        let r1 = random_double();
+        let r2 = random_double();
+
+        let z = 1. - r2 * (1. - self.cos_theta_max);
+        let phi = 2. * PI * r1;
+        let sin_theta = (1. - z * z).max(0.).sqrt();
+
+        self.uvw
+            .local_vec(&Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z))
+    }
+}
+
+// Directions scattered by a phase function from light travelling along direction
+pub struct PhasePDF {
+    phase: PhaseFunction,
+    direction: Vec3,
+}
+
+impl PhasePDF {
+    pub fn new(phase: PhaseFunction, direction: &Vec3) -> AnyPDF {
+        AnyPDF::Phase(PhasePDF {
+            phase,
+            direction: unit_vector(direction),
+        })
+    }
+}
+
+impl PDF for PhasePDF {
+    fn value(&self, direction: &Vec3) -> f64 {
+        self.phase
+            .pdf(dot(&self.direction, &unit_vector(direction)))
+    }
+
+    fn generate(&self) -> Vec3 {
+        self.phase.sample(&self.direction)
+    }
+}
+
+// Reflections off the visible microfacets of a rough surface, for light leaving along wo
+pub struct MicrofacetPDF {
+    distribution: TrowbridgeReitz,
+    uvw: Onb,
+    wo: Vec3, // local to uvw
+}
+
+impl MicrofacetPDF {
+    pub fn new(distribution: TrowbridgeReitz, normal: &Vec3, wo: &Vec3) -> AnyPDF {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(normal);
+        let wo = to_local(&uvw, &unit_vector(wo));
+
+        AnyPDF::Microfacet(MicrofacetPDF {
+            distribution,
+            uvw,
+            wo,
+        })
+    }
+}
+
+impl PDF for MicrofacetPDF {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let wi = to_local(&self.uvw, &unit_vector(direction));
+        if wi.z() * self.wo.z() <= 0. {
+            return 0.;
+        }
+
+        let wm = unit_vector(&(self.wo + wi));
+        let wm = if wm.z() < 0. { -wm } else { wm };
+        let cos = dot(&self.wo, &wm);
+        if cos <= 0. {
+            return 0.;
+        }
+
+        // From the density of normals to the density of reflected directions
+        self.distribution.pdf(&self.wo, &wm) / (4. * cos)
+    }
+
+    fn generate(&self) -> Vec3 {
+        let wm = self.distribution.sample_wm(&self.wo);
+        let wi = 2. * dot(&self.wo, &wm) * wm - self.wo;
+        self.uvw.local_vec(&wi)
+    }
+}
+
+// Mirror reflections along reflected moved to a random point on a sphere of radius fuzz around it
+pub struct FuzzPDF {
+    reflected: Vec3,
+    fuzz: f64,
+}
+
+impl FuzzPDF {
+    pub fn new(reflected: &Vec3, fuzz: f64) -> FuzzPDF {
+        FuzzPDF {
+            reflected: unit_vector(reflected),
+            fuzz,
+        }
+    }
+}
+
+impl PDF for FuzzPDF {
+    fn value(&self, direction: &Vec3) -> f64 {
+        // Direction passes through the sphere at the t solving |t d - reflected| = fuzz, and the
+        // sphere's uniform density of 1 / (4 pi fuzz^2) there covers t^2 / cos of solid angle
+        let c = dot(&unit_vector(direction), &self.reflected);
+        let discriminant = c * c - 1. + self.fuzz * self.fuzz;
+        if discriminant <= 0. {
+            return 0.;
+        }
+
+        let root = discriminant.sqrt();
+        let density: f64 = [c - root, c + root]
+            .iter()
+            .filter(|t| **t > 0.)
+            .map(|t| t * t)
+            .sum();
+        density / (4. * PI * self.fuzz * root)
+    }
+
+    fn generate(&self) -> Vec3 {
+        self.reflected + self.fuzz * random_unit_vector()
+    }
+}
+
+// Reflections and refractions off the microfacets of a rough dielectric visible from wo, where eta
+// is the index on wo's side over the index on the other. Each microfacet reflects with its Fresnel
+// reflectance and refracts the rest, following Walter et al. "Microfacet Models for Refraction
+// through Rough Surfaces".
+pub struct DielectricPDF {
+    distribution: TrowbridgeReitz,
+    uvw: Onb,
+    wo: Vec3, // local to uvw
+    eta: f64,
+}
+
+impl DielectricPDF {
+    pub fn new(distribution: TrowbridgeReitz, normal: &Vec3, wo: &Vec3, eta: f64) -> DielectricPDF {
+        let mut uvw = Onb::default();
+        uvw.build_from_w(normal);
+        let wo = to_local(&uvw, &unit_vector(wo));
+
+        DielectricPDF {
+            distribution,
+            uvw,
+            wo,
+            eta,
+        }
+    }
+}
+
+impl PDF for DielectricPDF {
+    fn value(&self, direction: &Vec3) -> f64 {
+        let wi = to_local(&self.uvw, &unit_vector(direction));
+        let wm = match half_vector(&self.wo, &wi, self.eta) {
+            Some(wm) => wm,
+            None => return 0.,
+        };
+
+        let density = self.distribution.pdf(&self.wo, &wm);
+        let reflectance = dielectric_reflectance(dot(&self.wo, &wm), self.eta);
+
+        if wi.z() > 0. {
+            density / (4. * dot(&self.wo, &wm)) * reflectance
+        } else {
+            // From the density of normals to the density of refracted directions
+            let denom = dot(&wi, &wm) + self.eta * dot(&self.wo, &wm);
+            density * dot(&wi, &wm).abs() / (denom * denom) * (1. - reflectance)
+        }
+    }
+
+    fn generate(&self) -> Vec3 {
+        let wm = self.distribution.sample_wm(&self.wo);
+        let cos = dot(&self.wo, &wm);
+
+        let reflected = random_double() < dielectric_reflectance(cos, self.eta);
+        let wi = if reflected {
+            2. * cos * wm - self.wo
+        } else {
+            refract(&-self.wo, &wm, self.eta)
+        };
+
+        // Light a microfacet sends to the wrong side of the surface is lost, so it goes along the
+        // surface where value and the BSDF are both zero
+        if (wi.z() > 0.) != reflected {
+            return self.uvw.local_vec(&Vec3::new(wi.x(), wi.y(), 0.));
+        }
+        self.uvw.local_vec(&wi)
+    }
+}
+
 pub struct HittablePDF {
     objects: Arc<Object>,
     origin: Point3,
@@ -101,27 +331,133 @@ impl PDF for HittablePDF {
 
 pub struct MixturePDF {
     p0: Box<AnyPDF>,
-    p1: Box<AnyPDF>
+    p1: Box<AnyPDF>,
+    w0: f64, // probability of picking p0
 }
 
 impl MixturePDF {
     pub fn new(p0: Box<AnyPDF>, p1: Box<AnyPDF>) -> Self {
+        Self::weighted(p0, p1, 0.5)
+    }
+
+    pub fn weighted(p0: Box<AnyPDF>, p1: Box<AnyPDF>, w0: f64) -> Self {
         MixturePDF {
-            p0, p1
+            p0, p1, w0
         }
     }
 }
 
 impl PDF for MixturePDF {
     fn value(&self, direction: &Vec3) -> f64 {
-        0.5 * self.p0.value(direction) + 0.5 * self.p1.value(direction)
+        self.w0 * self.p0.value(direction) + (1. - self.w0) * self.p1.value(direction)
     }
 
     fn generate(&self) -> Vec3 {
-        if random_double() < 0.5 {
+        if random_double() < self.w0 {
             self.p0.generate()
         } else {
             self.p1.generate()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Integral over the sphere of the density of p around the z axis, counting the directions
+    // whose cosine with it falls in range
+    fn integrate(p: &AnyPDF, range: std::ops::Range<f64>) -> f64 {
+        let n = 200_000;
+        let d_mu = 2. / n as f64;
+        (0..n)
+            .map(|i| -1. + (i as f64 + 0.5) * d_mu)
+            .filter(|mu| range.contains(mu))
+            .map(|mu| p.value(&Vec3::new((1. - mu * mu).sqrt(), 0., mu)) * 2. * PI * d_mu)
+            .sum()
+    }
+
+    #[test]
+    fn fuzz_samples_follow_the_pdf() {
+        let bins = 10;
+        let n = 200_000;
+
+        for fuzz in [0.3, 0.8, 1.] {
+            let p = AnyPDF::Fuzz(FuzzPDF::new(&Vec3::new(0., 0., 1.), fuzz));
+
+            let integral = integrate(&p, -1.0..1.0);
+            assert!((integral - 1.).abs() < 1e-2, "pdf integrates to {}", integral);
+
+            let mut counts = vec![0; bins];
+            for _ in 0..n {
+                let mu = unit_vector(&p.generate()).z();
+                counts[(((mu + 1.) / 2. * bins as f64) as usize).min(bins - 1)] += 1;
+            }
+
+            for (i, count) in counts.iter().enumerate() {
+                let lo = -1. + 2. * i as f64 / bins as f64;
+                let expected = integrate(&p, lo..lo + 2. / bins as f64);
+                let frequency = *count as f64 / n as f64;
+                assert!(
+                    (frequency - expected).abs() < 0.01,
+                    "bin {} has {} of the samples, pdf says {}",
+                    i,
+                    frequency,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rough_glass_samples_follow_the_pdf() {
+        let bins = 10;
+        let n = 200_000;
+
+        // Straight on from air into glass and from glass into air, where steep microfacets reflect
+        // everything past the critical angle
+        for (roughness, eta) in [(0.3, 1. / 1.5), (0.7, 1. / 1.5), (0.7, 1.5)] {
+            let normal = Vec3::new(0., 0., 1.);
+            let p = AnyPDF::Dielectric(DielectricPDF::new(
+                TrowbridgeReitz::new(roughness),
+                &normal,
+                &normal,
+                eta,
+            ));
+
+            // Samples sent to the wrong side of the surface come back along it, see generate
+            let mut counts = vec![0; bins];
+            let mut lost = 0;
+            for _ in 0..n {
+                let mu = unit_vector(&p.generate()).z();
+                if mu == 0. {
+                    lost += 1;
+                } else {
+                    counts[(((mu + 1.) / 2. * bins as f64) as usize).min(bins - 1)] += 1;
+                }
+            }
+
+            let integral = integrate(&p, -1.0..1.0);
+            let kept = 1. - lost as f64 / n as f64;
+            assert!(
+                (integral - kept).abs() < 1e-2,
+                "pdf integrates to {} for {} of the samples",
+                integral,
+                kept
+            );
+
+            for (i, count) in counts.iter().enumerate() {
+                let lo = -1. + 2. * i as f64 / bins as f64;
+                let expected = integrate(&p, lo..lo + 2. / bins as f64);
+                let frequency = *count as f64 / n as f64;
+                assert!(
+                    (frequency - expected).abs() < 0.01,
+                    "bin {} has {} of the samples, pdf says {}",
+                    i,
+                    frequency,
+                    expected
+                );
+            }
+        }
+    }
+}
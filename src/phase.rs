@@ -0,0 +1,148 @@
+use std::f64::consts::PI;
+
+use crate::{
+    onb::Onb,
+    utils::random_double,
+    vec3::{unit_vector, Vec3},
+};
+
+// How a participating medium spreads light over directions. cos_theta is the cosine of the angle
+// between the direction light was travelling and the direction it leaves in, so positive g
+// means forward scattering.
+#[derive(Clone, Copy)]
+pub enum PhaseFunction {
+    Isotropic,
+    // g in (-1, 1), from back scattering through isotropic at 0 to forward scattering
+    HenyeyGreenstein { g: f64 },
+    // Scattering by particles much smaller than the wavelength, like air molecules
+    Rayleigh,
+    // Cornette-Shanks approximation of Mie scattering by larger particles such as haze and fog droplets
+    Mie { g: f64 },
+}
+
+impl PhaseFunction {
+    pub fn value(&self, cos_theta: f64) -> f64 {
+        match *self {
+            PhaseFunction::Isotropic => 1. / (4. * PI),
+            PhaseFunction::HenyeyGreenstein { g } => henyey_greenstein(cos_theta, g),
+            PhaseFunction::Rayleigh => 3. / (16. * PI) * (1. + cos_theta * cos_theta),
+            PhaseFunction::Mie { g } => {
+                let g2 = g * g;
+                let denom = (1. + g2 - 2. * g * cos_theta).max(1e-12).powf(1.5);
+                3. * (1. - g2) * (1. + cos_theta * cos_theta) / (8. * PI * (2. + g2) * denom)
+            }
+        }
+    }
+
+    // Density of the directions sample picks. Mie is sampled with the Henyey-Greenstein lobe of
+    // the same g, which has almost the same shape.
+    pub fn pdf(&self, cos_theta: f64) -> f64 {
+        match *self {
+            PhaseFunction::Mie { g } => henyey_greenstein(cos_theta, g),
+            _ => self.value(cos_theta),
+        }
+    }
+
+    // The cosine of a scattering angle distributed by pdf
+    fn sample_cos_theta(&self) -> f64 {
+        let u = random_double();
+
+        match *self {
+            PhaseFunction::Isotropic => 1. - 2. * u,
+            PhaseFunction::HenyeyGreenstein { g } | PhaseFunction::Mie { g } => {
+                if g.abs() < 1e-3 {
+                    return 1. - 2. * u;
+                }
+                let s = (1. - g * g) / (1. - g + 2. * g * u);
+                ((1. + g * g - s * s) / (2. * g)).clamp(-1., 1.)
+            }
+            PhaseFunction::Rayleigh => {
+                // Inverts the cdf (mu^3 + 3 mu + 4) / 8 with Cardano's formula
+                let q = 4. - 8. * u;
+                let root = (q * q / 4. + 1.).sqrt();
+                ((-q / 2. + root).cbrt() + (-q / 2. - root).cbrt()).clamp(-1., 1.)
+            }
+        }
+    }
+
+    // A scattered direction for light travelling along direction
+    pub fn sample(&self, direction: &Vec3) -> Vec3 {
+        let cos_theta = self.sample_cos_theta();
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = 2. * PI * random_double();
+
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&unit_vector(direction));
+        uvw.local_vec(&Vec3::new(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+}
+
+fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let g2 = g * g;
+    let denom = (1. + g2 - 2. * g * cos_theta).max(1e-12);
+    (1. - g2) / (4. * PI * denom * denom.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Integral over the sphere of f, a function of the cosine only
+    fn integrate(f: impl Fn(f64) -> f64) -> f64 {
+        let n = 200_000;
+        let d_mu = 2. / n as f64;
+        (0..n)
+            .map(|i| f(-1. + (i as f64 + 0.5) * d_mu) * 2. * PI * d_mu)
+            .sum()
+    }
+
+    #[test]
+    fn pdfs_integrate_to_one() {
+        let phases = [
+            PhaseFunction::Isotropic,
+            PhaseFunction::HenyeyGreenstein { g: 0. },
+            PhaseFunction::HenyeyGreenstein { g: 0.5 },
+            PhaseFunction::HenyeyGreenstein { g: -0.3 },
+            PhaseFunction::HenyeyGreenstein { g: 0.9 },
+            PhaseFunction::Rayleigh,
+            PhaseFunction::Mie { g: 0.7 },
+        ];
+
+        for phase in phases {
+            let integral = integrate(|mu| phase.pdf(mu));
+            assert!((integral - 1.).abs() < 1e-4, "pdf integrates to {}", integral);
+        }
+    }
+
+    #[test]
+    fn samples_follow_the_pdf() {
+        let bins = 10;
+        let n = 200_000;
+
+        for phase in [PhaseFunction::HenyeyGreenstein { g: 0.6 }, PhaseFunction::Rayleigh] {
+            let mut counts = vec![0; bins];
+            for _ in 0..n {
+                let mu = phase.sample_cos_theta();
+                counts[(((mu + 1.) / 2. * bins as f64) as usize).min(bins - 1)] += 1;
+            }
+
+            for (i, count) in counts.iter().enumerate() {
+                let lo = -1. + 2. * i as f64 / bins as f64;
+                let hi = lo + 2. / bins as f64;
+                let expected = integrate(|mu| if (lo..hi).contains(&mu) { phase.pdf(mu) } else { 0. });
+                let frequency = *count as f64 / n as f64;
+                assert!(
+                    (frequency - expected).abs() < 0.01,
+                    "bin {} has {} of the samples, pdf says {}",
+                    i,
+                    frequency,
+                    expected
+                );
+            }
+        }
+    }
+}
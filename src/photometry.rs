@@ -0,0 +1,45 @@
+use crate::color::{luminance, Color};
+
+// Lumens per watt of radiant power at 555nm, where the eye is most sensitive. Lights given in
+// watts are treated as if all of their power were visible at this efficacy.
+pub const LUMENS_PER_WATT: f64 = 683.;
+
+// How bright a light is in physical units. Scenes lit this way should be modelled in metres,
+// which makes a radiance of 1 in the renderer a luminance of 1 nit (candela per square metre).
+// Set the camera's exposure_value to photograph them.
+#[derive(Clone, Copy)]
+pub enum LightUnit {
+    Watts(f64),   // radiant power
+    Lumens(f64),  // luminous flux, all the visible light leaving the light
+    Candela(f64), // luminous intensity in the brightest direction
+}
+
+impl LightUnit {
+    // Peak intensity in candela of a light spreading its flux over solid_angle steradians, where
+    // each direction is weighted by how much of the peak intensity it gets
+    pub fn candela(&self, solid_angle: f64) -> f64 {
+        match *self {
+            LightUnit::Watts(watts) => watts * LUMENS_PER_WATT / solid_angle,
+            LightUnit::Lumens(lumens) => lumens / solid_angle,
+            LightUnit::Candela(candela) => candela,
+        }
+    }
+}
+
+// color scaled to a luminance of 1, so that only its hue is left for a light unit to scale.
+// spectrum::blackbody colors already are.
+pub fn chromaticity(color: &Color) -> Color {
+    let y = luminance(color);
+    if y > 0. {
+        *color / y
+    } else {
+        Color::new_zero()
+    }
+}
+
+// How much to scale luminance by for a camera set to ev100, the exposure value at ISO 100 that a
+// photographer would pick for the scene. Uses the saturation based sensitivity of ISO 12232, which
+// makes a luminance of 1.2 * 2^ev100 nits just reach white.
+pub fn exposure_scale(ev100: f64) -> f64 {
+    1. / (1.2 * 2_f64.powf(ev100))
+}
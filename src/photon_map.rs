@@ -0,0 +1,373 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
+use std::sync::{Arc, OnceLock};
+
+use rayon::prelude::*;
+
+use crate::{
+    color::Color,
+    hittable::{HitRecord, Hittable},
+    integrator::{
+        clamp_indirect, sample_delta_lights, sample_scatter, Integrate, Integrator, Scene,
+    },
+    interval::Interval,
+    material::{MatFn, SrecData},
+    medium::PathMedium,
+    onb::Onb,
+    ray::Ray,
+    utils::random_double,
+    vec3::{dot, random_cosine_direction, unit_vector, Point3, Vec3},
+};
+
+// Path tracing with a caustic photon map. Photons are shot from scene.lights and stored where they
+// land on a diffuse surface after one or more mirror or glass bounces. Camera paths use the photons
+// for those light paths instead of trying to hit the light through the glass themselves.
+#[derive(Clone)]
+pub struct PhotonMapper {
+    photons: usize,
+    nearest: usize,
+    max_radius: f64,
+    map: Arc<OnceLock<PhotonMap>>, // shot for the first scene rendered
+}
+
+impl PhotonMapper {
+    // Shoots photons from the lights and estimates caustics from the nearest photons within max_radius
+    pub fn new(photons: usize, nearest: usize, max_radius: f64) -> Integrator {
+        Integrator::Photon(PhotonMapper {
+            photons,
+            nearest,
+            max_radius,
+            map: Arc::new(OnceLock::new()),
+        })
+    }
+
+    fn map(&self, scene: &Scene) -> &PhotonMap {
+        self.map.get_or_init(|| {
+            eprintln!("Shooting {} photons", self.photons);
+            let map = PhotonMap::new(self.shoot(scene));
+            eprintln!("Stored {} caustic photons", map.len());
+            map
+        })
+    }
+
+    fn shoot(&self, scene: &Scene) -> Vec<Photon> {
+        (0..self.photons)
+            .into_par_iter()
+            .filter_map(|_| self.trace_photon(scene))
+            .collect()
+    }
+
+    fn trace_photon(&self, scene: &Scene) -> Option<Photon> {
+        let time = random_double();
+        let (rec, mut pdf_pos) = scene.lights.sample_surface(time)?;
+
+        // Two sided lights shoot from either side with equal probability
+        let mut normal = rec.normal;
+        if rec.mat.is_two_sided() {
+            if random_double() < 0.5 {
+                normal = -normal;
+            }
+            pdf_pos *= 0.5;
+        }
+
+        let mut uvw = Onb::default();
+        uvw.build_from_w(&normal);
+        let direction = uvw.local_vec(&random_cosine_direction());
+
+        // The emitted light comes from whatever is in the world at that point, scene.lights only
+        // decides where photons start. Looking back along the photon's direction picks up lights
+        // whose emission changes with direction.
+        let probe = Ray::new_timed(rec.p + 0.001 * direction, -direction, time);
+        let light_rec = scene.world.hit(
+            &probe,
+            &Interval {
+                min: 0.0001,
+                max: 0.002,
+            },
+        )?;
+        let le = light_rec
+            .mat
+            .emitted(&probe, &light_rec, light_rec.u, light_rec.v, &light_rec.p);
+
+        // The cosine at the light cancels with the cosine weighted direction pdf
+        let mut power = le * PI / (pdf_pos * self.photons as f64);
+        let mut r = Ray::new_timed(rec.p, direction, time);
+        let mut specular = false;
+        let mut medium = PathMedium::default();
+
+        for _ in 0..scene.cam.max_depth {
+            let (hit, transmittance) = medium.hit(scene, &r);
+            power = power * transmittance;
+
+            let rec = hit?;
+            let srec = medium.scatter(&r, &rec)?;
+
+            match srec.data {
+                SrecData::SkipRay(skip_ray) => {
+                    power = power * srec.attenuation;
+                    specular = true;
+                    medium.cross(&rec, &skip_ray);
+                    r = skip_ray;
+                }
+                SrecData::PdfPtr(_) => {
+                    if !specular || !on_surface(&rec) {
+                        return None;
+                    }
+
+                    return Some(Photon {
+                        p: rec.p,
+                        direction: unit_vector(&r.direction()),
+                        power,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    // Reflected radiance from the caustic photons around rec, in medium
+    fn caustics(&self, r: &Ray, rec: &HitRecord, map: &PhotonMap, medium: &PathMedium) -> Color {
+        let (neighbors, radius_squared) =
+            map.nearest(&rec.p, self.nearest, self.max_radius * self.max_radius);
+
+        let flux = neighbors
+            .iter()
+            .map(|photon| {
+                let cosine = dot(&-photon.direction, &rec.normal);
+                if cosine <= 0. {
+                    return Color::new_zero();
+                }
+
+                let towards_light = Ray::new_timed(rec.p, -photon.direction, r.time());
+                medium.eval(r, rec, &towards_light) / cosine * photon.power
+            })
+            .fold(Color::new_zero(), |acc, c| acc + c);
+
+        flux / (PI * radius_squared)
+    }
+
+    fn trace(&self, r: &Ray, scene: &Scene, map: &PhotonMap) -> Color {
+        let mut r = r.clone();
+        let mut throughput = Color::new(1., 1., 1.);
+        let mut radiance = Color::new_zero();
+
+        // Set after a diffuse surface whose caustics came from the photon map,
+        // cleared as soon as the path stops being purely specular after it
+        let mut caustic_chain = false;
+        let mut last_specular = false;
+        let mut medium = PathMedium::default();
+
+        for depth in 0..scene.cam.max_depth {
+            let (hit, transmittance) = medium.hit(scene, &r);
+            throughput = throughput * transmittance;
+
+            let rec = match hit {
+                Some(rec) => rec,
+                None => {
+                    let background = throughput * scene.cam.background.value(&r);
+                    radiance = radiance + clamp_indirect(scene.cam, background, depth);
+                    break;
+                }
+            };
+
+            // Light reaching a diffuse surface through glass was already counted by the photons
+            if !(caustic_chain && last_specular) {
+                let color_from_emission = rec.mat.emitted(&r, &rec, rec.u, rec.v, &rec.p);
+                radiance =
+                    radiance + clamp_indirect(scene.cam, throughput * color_from_emission, depth);
+            }
+
+            let srec = match medium.scatter(&r, &rec) {
+                Some(srec) => srec,
+                None => break,
+            };
+
+            match srec.data {
+                SrecData::SkipRay(skip_ray) => {
+                    throughput = throughput * srec.attenuation;
+                    last_specular = true;
+                    medium.cross(&rec, &skip_ray);
+                    r = skip_ray;
+                }
+                SrecData::PdfPtr(pdf_ptr) => {
+                    if on_surface(&rec) && !map.is_empty() {
+                        radiance = radiance + throughput * self.caustics(&r, &rec, map, &medium);
+                        caustic_chain = true;
+                    } else {
+                        caustic_chain = false;
+                    }
+                    last_specular = false;
+
+                    let direct = throughput * sample_delta_lights(&r, &rec, scene, &medium);
+                    radiance = radiance + clamp_indirect(scene.cam, direct, depth + 1);
+
+                    let (scattered, weight) = sample_scatter(&r, &rec, pdf_ptr, scene, &medium);
+                    throughput = throughput * weight;
+                    medium.cross(&rec, &scattered);
+                    r = scattered;
+                }
+            }
+        }
+
+        radiance
+    }
+}
+
+impl Integrate for PhotonMapper {
+    fn prepare(&self, scene: &Scene) {
+        self.map(scene);
+    }
+
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        self.trace(r, scene, self.map(scene))
+    }
+}
+
+// Volumes have no surface to gather photons on
+fn on_surface(rec: &HitRecord) -> bool {
+    !rec.mat.is_volumetric()
+}
+
+pub struct Photon {
+    p: Point3,
+    direction: Vec3, // direction of travel when the photon landed
+    power: Color,
+}
+
+struct Neighbor {
+    distance_squared: f64,
+    index: usize,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance_squared == other.distance_squared
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_squared.total_cmp(&other.distance_squared)
+    }
+}
+
+// A balanced kd-tree stored in place: the median of every range is the splitting node,
+// with the lower half to its left and the upper half to its right
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>) -> PhotonMap {
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+
+        PhotonMap { photons, axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [u8]) {
+        if photons.is_empty() {
+            return;
+        }
+
+        // Split along the axis with the largest extent
+        let mut min = photons[0].p;
+        let mut max = photons[0].p;
+        for photon in photons.iter() {
+            for c in 0..3 {
+                min.set(c, min.dim(c).min(photon.p.dim(c)));
+                max.set(c, max.dim(c).max(photon.p.dim(c)));
+            }
+        }
+        let extent = max - min;
+        let axis = (0..3)
+            .max_by(|a, b| extent.dim(*a).total_cmp(&extent.dim(*b)))
+            .unwrap_or(0);
+
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| a.p.dim(axis).total_cmp(&b.p.dim(axis)));
+        axes[mid] = axis;
+
+        let (left, right) = photons.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    // The k nearest photons to p closer than sqrt(max_distance_squared), and the squared radius
+    // of the disc they were gathered from
+    pub fn nearest(&self, p: &Point3, k: usize, max_distance_squared: f64) -> (Vec<&Photon>, f64) {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut radius_squared = max_distance_squared;
+        self.search(0, self.photons.len(), p, k, &mut heap, &mut radius_squared);
+
+        let neighbors = heap.into_iter().map(|n| &self.photons[n.index]).collect();
+        (neighbors, radius_squared)
+    }
+
+    fn search(
+        &self,
+        lo: usize,
+        hi: usize,
+        p: &Point3,
+        k: usize,
+        heap: &mut BinaryHeap<Neighbor>,
+        radius_squared: &mut f64,
+    ) {
+        if lo >= hi {
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        let delta = p.dim(self.axes[mid]) - photon.p.dim(self.axes[mid]);
+
+        let (near, far) = if delta < 0. {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search(near.0, near.1, p, k, heap, radius_squared);
+
+        let distance_squared = (photon.p - *p).length_squared();
+        if distance_squared < *radius_squared {
+            heap.push(Neighbor {
+                distance_squared,
+                index: mid,
+            });
+            if heap.len() > k {
+                heap.pop();
+            }
+            if heap.len() == k {
+                if let Some(farthest) = heap.peek() {
+                    *radius_squared = farthest.distance_squared;
+                }
+            }
+        }
+
+        if delta * delta < *radius_squared {
+            self.search(far.0, far.1, p, k, heap, radius_squared);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::{
+    color::Color,
+    hittable::Hittable,
+    integrator::{Integrate, Integrator, Scene},
+    interval::Interval,
+    material::Material,
+    onb::Onb,
+    ray::Ray,
+    utils::INF,
+    vec3::random_cosine_direction,
+};
+
+// Quick looks at the geometry of a scene that skip light transport entirely
+#[derive(Clone)]
+pub enum PreviewMode {
+    // Fraction of cosine weighted rays that escape further than distance
+    AmbientOcclusion { samples: u32, distance: f64 },
+    // Outward normals mapped from [-1, 1] to [0, 1]
+    Normals,
+    Uv,
+    // Brightest at the camera, black at max and beyond
+    Distance { max: f64 },
+    // A different color for every material
+    MaterialIndex,
+    // BVH nodes visited by the camera ray, from blue at none to red at max
+    BvhTraversal { max: u32 },
+}
+
+#[derive(Clone)]
+pub struct Preview {
+    mode: PreviewMode,
+}
+
+impl Preview {
+    pub fn new(mode: PreviewMode) -> Integrator {
+        Integrator::Preview(Preview { mode })
+    }
+
+    pub fn ambient_occlusion(samples: u32, distance: f64) -> Integrator {
+        Preview::new(PreviewMode::AmbientOcclusion { samples, distance })
+    }
+
+    pub fn normals() -> Integrator {
+        Preview::new(PreviewMode::Normals)
+    }
+
+    pub fn uv() -> Integrator {
+        Preview::new(PreviewMode::Uv)
+    }
+
+    pub fn distance(max: f64) -> Integrator {
+        Preview::new(PreviewMode::Distance { max })
+    }
+
+    pub fn material_index() -> Integrator {
+        Preview::new(PreviewMode::MaterialIndex)
+    }
+
+    pub fn bvh_traversal(max: u32) -> Integrator {
+        Preview::new(PreviewMode::BvhTraversal { max })
+    }
+}
+
+impl Integrate for Preview {
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        if let PreviewMode::BvhTraversal { max } = self.mode {
+            let mut visits = 0;
+            scene.world.hit_counting(
+                r,
+                &Interval {
+                    min: 0.0001,
+                    max: INF,
+                },
+                &mut visits,
+            );
+
+            let t = (visits as f64 / max as f64).min(1.);
+            return Color::new(t, 0., 1. - t);
+        }
+
+        let rec = match scene.hit(r) {
+            Some(rec) => rec,
+            None => return Color::new_zero(),
+        };
+
+        match self.mode {
+            PreviewMode::AmbientOcclusion { samples, distance } => {
+                let mut uvw = Onb::default();
+                uvw.build_from_w(&rec.normal);
+
+                let open = (0..samples)
+                    .filter(|_| {
+                        let ao_ray = Ray::new_timed(
+                            rec.p,
+                            uvw.local_vec(&random_cosine_direction()),
+                            r.time(),
+                        );
+                        scene
+                            .world
+                            .hit(
+                                &ao_ray,
+                                &Interval {
+                                    min: 0.0001,
+                                    max: distance,
+                                },
+                            )
+                            .is_none()
+                    })
+                    .count();
+
+                let visibility = open as f64 / samples.max(1) as f64;
+                Color::new(visibility, visibility, visibility)
+            }
+            PreviewMode::Normals => {
+                let outward = if rec.front_face {
+                    rec.normal
+                } else {
+                    -rec.normal
+                };
+                0.5 * (outward + Color::new(1., 1., 1.))
+            }
+            PreviewMode::Uv => Color::new(rec.u, rec.v, 0.),
+            PreviewMode::Distance { max } => {
+                let depth = 1. - (rec.t * r.direction().length() / max).min(1.);
+                Color::new(depth, depth, depth)
+            }
+            PreviewMode::MaterialIndex => material_color(rec.mat),
+            PreviewMode::BvhTraversal { .. } => unreachable!(),
+        }
+    }
+}
+
+// Every material instance gets its own color, from a hash of where it lives
+fn material_color(mat: &Material) -> Color {
+    let mut h = mat as *const Material as usize as u64;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+
+    let channel = |shift: u64| 0.2 + 0.8 * ((h >> shift) & 0xff) as f64 / 255.;
+    Color::new(channel(0), channel(8), channel(16))
+}
@@ -5,6 +5,7 @@ pub struct Ray {
     orig: Point3,
     dir: Vec3,
     tm: f64,
+    lambda: Option<f64>, // wavelength in nanometers, for spectral rendering
 }
 
 impl Ray {
@@ -14,6 +15,7 @@ impl Ray {
             orig: origin,
             dir: direction,
             tm: time,
+            lambda: None,
         }
     }
 
@@ -22,9 +24,14 @@ impl Ray {
             orig: origin,
             dir: direction,
             tm: 0.,
+            lambda: None,
         }
     }
 
+    pub fn with_wavelength(self, lambda: Option<f64>) -> Self {
+        Self { lambda, ..self }
+    }
+
     // Accessors
     pub fn direction(&self) -> Vec3 {
         self.dir
@@ -38,6 +45,10 @@ impl Ray {
         self.tm
     }
 
+    pub fn wavelength(&self) -> Option<f64> {
+        self.lambda
+    }
+
     // Utils
     pub fn at(&self, t: f64) -> Vec3 {
         self.orig + t * self.dir
@@ -1,15 +1,20 @@
 use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
 
-use crate::color::{write_color, Color};
-use crate::hittable::{Hittable, HittableList};
-use crate::interval::Interval;
-use crate::material::{MatFn, SrecData};
+use crate::color::{luminance, write_color, Color};
+use crate::delta_light::DeltaLight;
+use crate::denoise::{denoise, Guides};
+use crate::hittable::HittableList;
+use crate::integrator::{Integrate, Integrator, PathTracer, Scene};
+use crate::lights::light_sampler;
 use crate::object::{Object, Sun};
-use crate::pdf::{CosinePDF, HittablePDF, MixturePDF, PDF};
+use crate::photometry::exposure_scale;
+use crate::sky::{Background, Sky};
 use crate::ray::Ray;
-use crate::utils::{random_double, INF};
+use crate::utils::random_double;
 use crate::vec3::{cross, dot, random_in_unit_disk, unit_vector, Point3, Vec3};
+use image::{ImageBuffer, Rgb};
 use rayon::prelude::*;
 
 pub struct Camera {
@@ -22,8 +27,14 @@ pub struct Camera {
     pub vup: Vec3,
     pub defocus_angle: f64,
     pub focus_dist: f64,
-    pub background: Color,
+    pub background: Background,
     pub auto_exposure: bool,
+    pub exposure_value: Option<f64>, // EV100 to photograph scenes lit in physical units, see photometry
+    pub integrator: Integrator,
+    pub denoise: bool, // filter the image with the albedo, normal and depth of the scene as guides
+    pub clamp_indirect: Option<f64>, // largest value one path may add after two or more bounces
+    pub reject_outliers: Option<f64>, // drop samples this many standard deviations above a pixel's mean
+    pub light_groups: bool, // also write the light of every light group to its own image
     image_height: i32,
     center: Point3,
     pixel00_loc: Point3,
@@ -53,7 +64,10 @@ impl Default for Camera {
             Vec3::new(0., 1., 0.),
             0.,
             10.,
-            Color::new(0.70, 0.80, 1.00),
+            Sky::new(
+                Sun::from_angles(40., 30., Color::new(0.7, 0.7, 0.7), 0.53),
+                2.5,
+            ),
         )
     }
 }
@@ -70,7 +84,7 @@ impl Camera {
         vup: Vec3,
         defocus_angle: f64,
         focus_dist: f64,
-        background: Color,
+        background: impl Into<Background>,
     ) -> Self {
         // Calculate the image height, ensure that it's at least 1
         let image_height = (image_width as f64 / aspect_ratio) as i32;
@@ -125,8 +139,14 @@ impl Camera {
             focus_dist,
             defocus_disk_u: u * defocus_radius,
             defocus_disk_v: v * defocus_radius,
-            background,
+            background: background.into(),
             auto_exposure: false,
+            exposure_value: None,
+            integrator: PathTracer::new(),
+            denoise: false,
+            clamp_indirect: None,
+            reject_outliers: None,
+            light_groups: false,
             sqrt_spp: sqrt_spp as i32,
             recip_sqrt_spp: 1. / sqrt_spp,
         }
@@ -137,15 +157,24 @@ pub fn init_pixels(cam: &Camera) -> Vec<Color> {
     vec![Color::new_zero(); (cam.image_height * cam.image_width) as usize]
 }
 
-pub fn render_par(cam: &Camera, world: &HittableList, pixels: &mut Vec<Color>, suns: &Vec<Sun>) {
-    render_par_lights(cam, world, pixels, suns, Arc::new(Object::List(Arc::new(HittableList::new()))))
+// Renders with every emitter in world as the lights to sample
+pub fn render_par(
+    cam: &Camera,
+    world: &HittableList,
+    pixels: &mut Vec<Color>,
+    delta_lights: &Vec<DeltaLight>,
+) {
+    let lights = world.collect_lights();
+    eprintln!("Found {} lights", lights.objects.len());
+
+    render_par_lights(cam, world, pixels, delta_lights, Arc::new(light_sampler(lights)))
 }
 
 pub fn render_par_lights(
     cam: &Camera,
     world: &HittableList,
     pixels: &mut Vec<Color>,
-    suns: &Vec<Sun>,
+    delta_lights: &Vec<DeltaLight>,
     lights: Arc<Object>,
 ) {
     println!("P3\n{} {}\n255", cam.image_width, cam.image_height);
@@ -167,34 +196,62 @@ pub fn render_par_lights(
         Err(_) => eprintln!("Could not set threads, rayon will use the default threads."),
     };
 
-    // let chunk_size = ((cam.image_height * cam.image_width) as f64 / (threads * 12) as f64) as usize;
-    let chunk_size = (cam.image_width * 3) as usize;
-
-    let rows: Vec<(usize, &mut [Color])> = pixels.chunks_mut(chunk_size).enumerate().collect();
-    let len = rows.len();
-
-    let progress_chunk = 100. / len as f64;
+    let mut light_groups = vec!["default".to_string()];
+    light_groups.extend(world.light_groups());
+    for group in delta_lights.iter().filter_map(|l| l.group()) {
+        if !light_groups.iter().any(|g| g == group) {
+            light_groups.push(group.to_string());
+        }
+    }
+    light_groups.push("background".to_string());
+
+    let scene = Scene {
+        world,
+        delta_lights,
+        lights,
+        cam,
+        light_groups,
+    };
+    cam.integrator.prepare(&scene);
 
-    let counter = std::sync::Mutex::new(0);
-    rows.into_par_iter().for_each(|(j, row)| {
-        for i in 0..row.len() {
-            let idx = (j * chunk_size + i) as i32;
-            let x = idx as i32 % cam.image_width;
-            let y = idx / cam.image_width;
+    // One image per light group, which add up to pixels
+    let mut layers = match cam.light_groups {
+        true => vec![init_pixels(cam); scene.light_groups.len()],
+        false => vec![],
+    };
 
-            for s_j in 0..cam.sqrt_spp as i32 {
-                for s_i in 0..cam.sqrt_spp as i32 {
-                    let r = get_ray(cam, x, y, s_i, s_j);
-                    let color = ray_color(&r, cam.max_depth, world, suns, cam, lights.clone());
-                    row[i] = row[i] + color;
-                }
+    reset_sample_stats();
+    if cam.light_groups {
+        eprintln!("Rendering {} light groups", layers.len());
+        cam.integrator.render_groups(&scene, &mut layers);
+        for layer in layers.iter() {
+            for (pixel, c) in pixels.iter_mut().zip(layer) {
+                *pixel = *pixel + *c;
             }
         }
+    } else {
+        cam.integrator.render(&scene, pixels);
+    }
+    report_sample_stats(cam);
 
-        let mut counter = counter.lock().expect("should work");
-        *counter += 1;
-        eprint!("\rProgress {:.1}%", *counter as f64 * progress_chunk);
-    });
+    if cam.denoise {
+        eprintln!("\rDenoising...            ");
+        let width = cam.image_width as usize;
+        let guides = Guides::new(&scene, width, cam.image_height as usize);
+        denoise(pixels, &mut layers, cam.samples_per_pixel as f64, &guides, width);
+    }
+
+    if let Some(ev100) = cam.exposure_value {
+        let scale = exposure_scale(ev100);
+        pixels
+            .iter_mut()
+            .chain(layers.iter_mut().flatten())
+            .for_each(|pixel| *pixel = *pixel * scale);
+    }
+
+    if cam.light_groups {
+        write_light_groups(&scene, &layers);
+    }
 
     eprintln!("\rWriting...            ");
 
@@ -215,6 +272,182 @@ pub fn render_par_lights(
     eprintln!("\rDone!                           ");
 }
 
+// Runs f on samples_per_pixel stratified camera rays per pixel, accumulating into pixels
+pub fn sample_pixels<F>(cam: &Camera, pixels: &mut [Color], f: F)
+where
+    F: Fn(&Ray) -> Color + Sync,
+{
+    sample_rows(cam, pixels, 1, |r, sample| sample[0] = sample[0] + f(r));
+}
+
+// Like sample_pixels, but f adds each sample's light into one entry per layer
+pub fn sample_layers<F>(cam: &Camera, layers: &mut [Vec<Color>], f: F)
+where
+    F: Fn(&Ray, &mut [Color]) + Sync,
+{
+    let groups = layers.len();
+    let pixels = (cam.image_width * cam.image_height) as usize;
+    let mut sums = vec![Color::new_zero(); pixels * groups];
+    sample_rows(cam, &mut sums, groups, f);
+
+    for (idx, pixel) in sums.chunks(groups).enumerate() {
+        for (layer, c) in layers.iter_mut().zip(pixel) {
+            layer[idx] = layer[idx] + *c;
+        }
+    }
+}
+
+// Samples the pixels in parallel a few rows at a time. values holds groups entries per pixel.
+fn sample_rows<F>(cam: &Camera, values: &mut [Color], groups: usize, f: F)
+where
+    F: Fn(&Ray, &mut [Color]) + Sync,
+{
+    // let chunk_size = ((cam.image_height * cam.image_width) as f64 / (threads * 12) as f64) as usize;
+    let chunk_size = (cam.image_width * 3) as usize;
+
+    let rows: Vec<(usize, &mut [Color])> =
+        values.chunks_mut(chunk_size * groups).enumerate().collect();
+    let len = rows.len();
+
+    let progress_chunk = 100. / len as f64;
+
+    let f = &f;
+    let counter = std::sync::Mutex::new(0);
+    rows.into_par_iter().for_each(|(j, row)| {
+        for (i, pixel) in row.chunks_mut(groups).enumerate() {
+            let idx = (j * chunk_size + i) as i32;
+            let x = idx as i32 % cam.image_width;
+            let y = idx / cam.image_width;
+
+            sample_pixel(cam, x, y, pixel, f);
+        }
+
+        let mut counter = counter.lock().expect("should work");
+        *counter += 1;
+        eprint!("\rProgress {:.1}%", *counter as f64 * progress_chunk);
+    });
+}
+
+// Adds the samples of the pixel at x, y into sum, one entry per group
+fn sample_pixel<F>(cam: &Camera, x: i32, y: i32, sum: &mut [Color], f: &F)
+where
+    F: Fn(&Ray, &mut [Color]),
+{
+    let groups = sum.len();
+    let ray = |n: usize| {
+        let (s_i, s_j) = (n as i32 % cam.sqrt_spp, n as i32 / cam.sqrt_spp);
+        get_ray(cam, x, y, s_i, s_j)
+    };
+
+    match cam.reject_outliers {
+        // f adds its light, so without rejection every sample can go straight into the sum
+        None => {
+            for n in 0..cam.samples_per_pixel as usize {
+                f(&ray(n), sum);
+            }
+        }
+        Some(k) => {
+            // Every sample's groups one after another
+            let mut samples = vec![Color::new_zero(); cam.samples_per_pixel as usize * groups];
+            for (n, sample) in samples.chunks_mut(groups).enumerate() {
+                f(&ray(n), sample);
+            }
+
+            let totals: Vec<Color> = samples
+                .chunks(groups)
+                .map(|sample| sample.iter().fold(Color::new_zero(), |acc, c| acc + *c))
+                .collect();
+            let (kept, scale) = reject_outliers(&totals, k);
+
+            for (sample, keep) in samples.chunks(groups).zip(kept) {
+                if keep {
+                    for (s, c) in sum.iter_mut().zip(sample) {
+                        *s = *s + *c * scale;
+                    }
+                }
+            }
+        }
+    }
+
+    SAMPLES.fetch_add(cam.samples_per_pixel as u64, AtomicOrdering::Relaxed);
+}
+
+// Which of a pixel's samples to keep, leaving out the ones whose luminance is more than k standard
+// deviations above the mean, and the scale that lets the kept ones stand in for all of them
+fn reject_outliers(samples: &[Color], k: f64) -> (Vec<bool>, f64) {
+    let n = samples.len() as f64;
+
+    let lum: Vec<f64> = samples.iter().map(luminance).collect();
+    let mean = lum.iter().sum::<f64>() / n;
+    let variance = lum.iter().map(|l| (l - mean) * (l - mean)).sum::<f64>() / n;
+    let threshold = mean + k * variance.sqrt();
+
+    let kept: Vec<bool> = lum.iter().map(|l| *l <= threshold).collect();
+    let count = kept.iter().filter(|k| **k).count();
+
+    REJECTED.fetch_add((samples.len() - count) as u64, AtomicOrdering::Relaxed);
+
+    if count == 0 {
+        return (kept, 0.);
+    }
+    (kept, n / count as f64)
+}
+
+// Writes the light of every light group into its own linear EXR image, light_group_<name>.exr
+fn write_light_groups(scene: &Scene, layers: &[Vec<Color>]) {
+    let cam = scene.cam;
+    let scale = 1. / cam.samples_per_pixel as f64;
+
+    for (layer, name) in layers.iter().zip(scene.light_groups.iter()) {
+        let image = ImageBuffer::from_fn(cam.image_width as u32, cam.image_height as u32, |x, y| {
+            let c = layer[(y * cam.image_width as u32 + x) as usize] * scale;
+            Rgb([c.x() as f32, c.y() as f32, c.z() as f32])
+        });
+
+        let filename = format!("light_group_{}.exr", name);
+        match image.save(&filename) {
+            Ok(_) => eprintln!("\rWrote {}", filename),
+            Err(e) => eprintln!("\rCould not write {}: {}", filename, e),
+        }
+    }
+}
+
+// How often the firefly controls kicked in during the last render
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+static CLAMPED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+pub fn count_clamped() {
+    CLAMPED.fetch_add(1, AtomicOrdering::Relaxed);
+}
+
+fn reset_sample_stats() {
+    SAMPLES.store(0, AtomicOrdering::Relaxed);
+    CLAMPED.store(0, AtomicOrdering::Relaxed);
+    REJECTED.store(0, AtomicOrdering::Relaxed);
+}
+
+fn report_sample_stats(cam: &Camera) {
+    let samples = SAMPLES.load(AtomicOrdering::Relaxed).max(1) as f64;
+
+    if cam.clamp_indirect.is_some() {
+        let clamped = CLAMPED.load(AtomicOrdering::Relaxed);
+        eprintln!(
+            "\rClamped {} path contributions ({:.4} per sample)",
+            clamped,
+            clamped as f64 / samples
+        );
+    }
+    if cam.reject_outliers.is_some() {
+        let rejected = REJECTED.load(AtomicOrdering::Relaxed);
+        eprintln!(
+            "\rRejected {} outlier samples ({:.4}% of samples)",
+            rejected,
+            100. * rejected as f64 / samples
+        );
+    }
+}
+
 fn get_ray(cam: &Camera, i: i32, j: i32, s_i: i32, s_j: i32) -> Ray {
     // Get a randomly sampled camera ray for the pixel at location i, j, originating from camera defocus disk
 
@@ -235,6 +468,23 @@ fn get_ray(cam: &Camera, i: i32, j: i32, s_i: i32, s_j: i32) -> Ray {
     Ray::new_timed(ray_origin, ray_direction, ray_time)
 }
 
+// Camera ray through the raster position (x, y), where pixel (i, j) covers [i, i + 1) x [j, j + 1)
+pub fn get_ray_raster(cam: &Camera, x: f64, y: f64) -> Ray {
+    let pixel_sample =
+        cam.pixel00_loc + ((x - 0.5) * cam.pixel_delta_u) + ((y - 0.5) * cam.pixel_delta_v);
+
+    let ray_origin = if cam.defocus_angle <= 0. {
+        cam.center
+    } else {
+        defocus_disk_sample(cam)
+    };
+
+    let ray_direction = pixel_sample - ray_origin;
+    let ray_time = random_double();
+
+    Ray::new_timed(ray_origin, ray_direction, ray_time)
+}
+
 fn defocus_disk_sample(cam: &Camera) -> Point3 {
     let p = random_in_unit_disk();
     cam.center + (p.x() * cam.defocus_disk_u) + (p.y() * cam.defocus_disk_v)
@@ -248,68 +498,6 @@ fn pixel_sample_square(cam: &Camera, s_i: i32, s_j: i32) -> Vec3 {
     px * cam.pixel_delta_u + py * cam.pixel_delta_v
 }
 
-fn ray_color(
-    r: &Ray,
-    depth: i32,
-    world: &HittableList,
-    suns: &Vec<Sun>,
-    cam: &Camera,
-    lights: Arc<Object>,
-) -> Color {
-    // check if we hit bounce limit
-    if depth <= 0 {
-        return Vec3::new_zero();
-    }
-
-    match world.hit(
-        r,
-        &Interval {
-            min: 0.0001,
-            max: INF,
-        },
-    ) {
-        Some(rec) => {
-            let color_from_emission = rec.mat.emitted(r, &rec, rec.u, rec.v, &rec.p);
-            match rec.mat.scatter(r, &rec) {
-                Some(srec) => match srec.data {
-                    SrecData::SkipRay(skip_ray) => 
-                        srec.attenuation
-                            * ray_color(&skip_ray, depth - 1, world, suns, cam, lights),
-                    SrecData::PdfPtr(pdf_ptr) => {
-                        let light_ptr =
-                            Box::new(HittablePDF::new(lights.clone(), rec.p));
-                        let p = MixturePDF::new(light_ptr, pdf_ptr);
-
-                        let scattered = Ray::new_timed(rec.p, p.generate(), r.time());
-
-                        let pdf_val = p.value(&scattered.direction());
-                        let scattering_pdf = rec.mat.scattering_pdf(r, &rec, &scattered);
-                        let sample_color =
-                            ray_color(&scattered, depth - 1, world, suns, cam, lights);
-                        let color_from_scatter =
-                            (srec.attenuation * scattering_pdf * sample_color) / pdf_val;
-
-                        color_from_emission + color_from_scatter
-                    }
-                },
-                None => color_from_emission,
-            }
-        }
-        None => {
-            // This sets the skybox + ambient light
-            /*let sun_light = match suns
-                .iter()
-                .map(|sun| sun.hit(&r) )
-                .reduce(|s1, s2| s1 + s2 ) {
-                    Some(light) => light,
-                    None => Color::new_zero()
-            };*/
-
-            cam.background //+ sun_light
-        }
-    }
-
-}
 fn draw_depth(cam: &Camera, depth_buffer: &Vec<i32>) {
     for depth in depth_buffer {
         let depth = *depth as f64 / cam.max_depth as f64;
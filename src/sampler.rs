@@ -0,0 +1,20 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+
+use crate::utils::{ACTIVE_STREAMS, SAMPLE_STREAM};
+
+pub use crate::utils::SampleStream;
+
+// Routes every random number drawn on this thread through stream, or back to the thread rng with None.
+// Kept out of utils since the small binaries sharing it never set one.
+pub fn set_sample_stream(stream: Option<Rc<RefCell<dyn SampleStream>>>) {
+    let setting = stream.is_some();
+    let was_set = SAMPLE_STREAM.with(|s| s.replace(stream).is_some());
+
+    match (was_set, setting) {
+        (false, true) => ACTIVE_STREAMS.fetch_add(1, Ordering::Relaxed),
+        (true, false) => ACTIVE_STREAMS.fetch_sub(1, Ordering::Relaxed),
+        _ => 0,
+    };
+}
@@ -0,0 +1,203 @@
+use std::f64::consts::PI;
+
+use crate::{
+    color::Color,
+    object::Sun,
+    ray::Ray,
+    vec3::{dot, unit_vector, Vec3},
+};
+
+// Scales the sky luminance (in kcd/m^2) down to the same range as the hand picked backgrounds
+const SKY_SCALE: f64 = 0.1;
+
+// Wavelengths (in micrometers) used to represent the red, green and blue channels
+const RGB_WAVELENGTHS: [f64; 3] = [0.680, 0.550, 0.440];
+
+#[derive(Clone)]
+pub enum Background {
+    Solid(Color),
+    Sky(Box<Sky>),
+}
+
+impl Background {
+    pub fn value(&self, r: &Ray) -> Color {
+        match self {
+            Background::Solid(c) => *c,
+            Background::Sky(s) => s.value(r),
+        }
+    }
+
+    pub fn sun(&self) -> Option<&Sun> {
+        match self {
+            Background::Solid(_) => None,
+            Background::Sky(s) => Some(&s.sun),
+        }
+    }
+}
+
+impl From<Color> for Background {
+    fn from(color: Color) -> Self {
+        Background::Solid(color)
+    }
+}
+
+impl From<Sky> for Background {
+    fn from(sky: Sky) -> Self {
+        Background::Sky(Box::new(sky))
+    }
+}
+
+// Coefficients of the Perez sky luminance distribution
+#[derive(Clone, Copy)]
+struct Perez {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+}
+
+impl Perez {
+    fn f(&self, cos_theta: f64, gamma: f64) -> f64 {
+        let cos_gamma = gamma.cos();
+        (1. + self.a * (self.b / cos_theta).exp())
+            * (1. + self.c * (self.d * gamma).exp() + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+// Preetham, Shirley & Smits "A Practical Analytic Model for Daylight"
+#[derive(Clone)]
+pub struct Sky {
+    sun: Sun,
+    sun_color: Color,
+    ground: Color,
+    perez_y: Perez,
+    perez_x: Perez,
+    perez_yy: Perez,
+    zenith: Vec3, // (Y, x, y) at the zenith, pre-divided by the Perez value there
+}
+
+impl Sky {
+    pub fn new(sun: Sun, turbidity: f64) -> Sky {
+        Self::new_with_ground(sun, turbidity, Color::new(0.3, 0.3, 0.3))
+    }
+
+    pub fn new_with_ground(sun: Sun, turbidity: f64, ground_albedo: Color) -> Sky {
+        let t = turbidity;
+
+        // The model is only valid for a sun above the horizon
+        let theta_s = sun.direction.y().clamp(0.001, 1.).acos();
+
+        let perez_y = Perez {
+            a: 0.1787 * t - 1.4630,
+            b: -0.3554 * t + 0.4275,
+            c: -0.0227 * t + 5.3251,
+            d: 0.1206 * t - 2.5771,
+            e: -0.0670 * t + 0.3703,
+        };
+        let perez_x = Perez {
+            a: -0.0193 * t - 0.2592,
+            b: -0.0665 * t + 0.0008,
+            c: -0.0004 * t + 0.2125,
+            d: -0.0641 * t - 0.8989,
+            e: -0.0033 * t + 0.0452,
+        };
+        let perez_yy = Perez {
+            a: -0.0167 * t - 0.2608,
+            b: -0.0950 * t + 0.0092,
+            c: -0.0079 * t + 0.2102,
+            d: -0.0441 * t - 1.6537,
+            e: -0.0109 * t + 0.0529,
+        };
+
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let theta_s2 = theta_s * theta_s;
+        let theta_s3 = theta_s2 * theta_s;
+        let t2 = t * t;
+
+        let zenith_x = t2 * (0.00166 * theta_s3 - 0.00375 * theta_s2 + 0.00209 * theta_s)
+            + t * (-0.02903 * theta_s3 + 0.06377 * theta_s2 - 0.03202 * theta_s + 0.00394)
+            + (0.11693 * theta_s3 - 0.21196 * theta_s2 + 0.06052 * theta_s + 0.25886);
+        let zenith_y = t2 * (0.00275 * theta_s3 - 0.00610 * theta_s2 + 0.00317 * theta_s)
+            + t * (-0.04214 * theta_s3 + 0.08970 * theta_s2 - 0.04153 * theta_s + 0.00516)
+            + (0.15346 * theta_s3 - 0.26756 * theta_s2 + 0.06670 * theta_s + 0.26688);
+
+        let zenith = Vec3::new(
+            zenith_luminance / perez_y.f(1., theta_s),
+            zenith_x / perez_x.f(1., theta_s),
+            zenith_y / perez_yy.f(1., theta_s),
+        );
+
+        Sky {
+            sun_color: Self::sun_transmittance(&sun, t),
+            sun,
+            ground: ground_albedo,
+            perez_y,
+            perez_x,
+            perez_yy,
+            zenith,
+        }
+    }
+
+    // Attenuation of direct sunlight through the atmosphere from Rayleigh and aerosol scattering
+    fn sun_transmittance(sun: &Sun, turbidity: f64) -> Color {
+        let elevation = sun.direction.y().clamp(0., 1.).asin().to_degrees();
+        let zenith_deg = 90. - elevation;
+
+        // Kasten-Young relative optical air mass
+        let air_mass =
+            1. / (zenith_deg.to_radians().cos() + 0.50572 * (96.07995 - zenith_deg).powf(-1.6364));
+
+        let beta = 0.04608 * turbidity - 0.04586;
+        let alpha = 1.3;
+
+        let tau = RGB_WAVELENGTHS.map(|lambda| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosol = beta * lambda.powf(-alpha);
+            (-(rayleigh + aerosol) * air_mass).exp()
+        });
+
+        Color::new(tau[0], tau[1], tau[2])
+    }
+
+    pub fn value(&self, r: &Ray) -> Color {
+        let direction = unit_vector(&r.direction());
+
+        // Below the horizon we see the ground lit by the sky at the horizon
+        if direction.y() < 0. {
+            let horizon = unit_vector(&Vec3::new(direction.x(), 0.01, direction.z()));
+            return self.ground * self.sky_radiance(&horizon);
+        }
+
+        self.sky_radiance(&direction) + self.sun_color * self.sun.hit(r)
+    }
+
+    fn sky_radiance(&self, direction: &Vec3) -> Color {
+        let cos_theta = direction.y().max(0.01);
+        let gamma = dot(direction, &self.sun.direction).clamp(-1., 1.).acos();
+
+        let luminance = self.zenith.x() * self.perez_y.f(cos_theta, gamma);
+        let x = self.zenith.y() * self.perez_x.f(cos_theta, gamma);
+        let y = self.zenith.z() * self.perez_yy.f(cos_theta, gamma);
+
+        xyy_to_rgb(x, y, luminance * SKY_SCALE)
+    }
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0. {
+        return Color::new_zero();
+    }
+
+    let cx = x * luminance / y;
+    let cy = luminance;
+    let cz = (1. - x - y) * luminance / y;
+
+    Color::new(
+        (3.2406 * cx - 1.5372 * cy - 0.4986 * cz).max(0.),
+        (-0.9689 * cx + 1.8758 * cy + 0.0415 * cz).max(0.),
+        (0.0557 * cx - 0.2040 * cy + 1.0570 * cz).max(0.),
+    )
+}
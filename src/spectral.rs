@@ -0,0 +1,120 @@
+use crate::{
+    color::Color,
+    integrator::{
+        clamp_indirect, sample_delta_lights_groups, sample_scatter, Integrate, Integrator, Scene,
+    },
+    material::{MatFn, SrecData},
+    medium::PathMedium,
+    ray::Ray,
+    render::sample_layers,
+    spectrum::{rgb_to_spectrum, SpectralToRgb, LAMBDA_MAX, LAMBDA_MIN},
+    utils::random_range,
+};
+
+// Path tracing one wavelength per path. Colors in the scene are turned into spectra as the path
+// meets them, so dispersive dielectrics can bend every wavelength by a different amount.
+#[derive(Clone)]
+pub struct SpectralPathTracer {
+    to_rgb: SpectralToRgb,
+}
+
+impl SpectralPathTracer {
+    pub fn new() -> Integrator {
+        Integrator::Spectral(SpectralPathTracer {
+            to_rgb: SpectralToRgb::new(),
+        })
+    }
+}
+
+impl SpectralPathTracer {
+    // Follows one path at a random wavelength from the camera, handing the color of every bit of
+    // light it finds to add along with the light group it came from
+    fn trace<F>(&self, r: &Ray, scene: &Scene, mut add: F)
+    where
+        F: FnMut(usize, Color),
+    {
+        let lambda = random_range(LAMBDA_MIN, LAMBDA_MAX);
+        let mut add =
+            |group: usize, radiance: f64| add(group, self.to_rgb.to_rgb(radiance, lambda));
+
+        let mut r = r.clone().with_wavelength(Some(lambda));
+        let mut throughput = 1.;
+        let mut medium = PathMedium::default();
+
+        for depth in 0..scene.cam.max_depth {
+            let (hit, transmittance) = medium.hit(scene, &r);
+            throughput *= rgb_to_spectrum(&transmittance, lambda);
+
+            let rec = match hit {
+                Some(rec) => rec,
+                None => {
+                    let background = scene.cam.background.value(&r);
+                    add(
+                        scene.background_group(),
+                        clamp(scene, throughput * rgb_to_spectrum(&background, lambda), depth),
+                    );
+                    break;
+                }
+            };
+
+            // Uses the reflectance basis, see rgb_to_spectrum
+            let color_from_emission = rec.mat.emitted(&r, &rec, rec.u, rec.v, &rec.p);
+            add(
+                scene.light_group(rec.mat),
+                clamp(
+                    scene,
+                    throughput * rgb_to_spectrum(&color_from_emission, lambda),
+                    depth,
+                ),
+            );
+
+            let srec = match medium.scatter(&r, &rec) {
+                Some(srec) => srec,
+                None => break,
+            };
+
+            if let SrecData::PdfPtr(_) = srec.data {
+                sample_delta_lights_groups(&r, &rec, scene, &medium, |group, direct| {
+                    add(
+                        group,
+                        clamp(scene, throughput * rgb_to_spectrum(&direct, lambda), depth + 1),
+                    )
+                });
+            }
+
+            r = match srec.data {
+                SrecData::SkipRay(skip_ray) => {
+                    throughput *= rgb_to_spectrum(&srec.attenuation, lambda);
+                    skip_ray
+                }
+                SrecData::PdfPtr(pdf_ptr) => {
+                    let (scattered, weight) = sample_scatter(&r, &rec, pdf_ptr, scene, &medium);
+                    throughput *= rgb_to_spectrum(&weight, lambda);
+                    scattered
+                }
+            }
+            .with_wavelength(Some(lambda));
+            medium.cross(&rec, &r);
+        }
+    }
+}
+
+impl Integrate for SpectralPathTracer {
+    fn ray_color(&self, r: &Ray, scene: &Scene) -> Color {
+        let mut radiance = Color::new_zero();
+        self.trace(r, scene, |_, c| radiance = radiance + c);
+        radiance
+    }
+
+    fn render_groups(&self, scene: &Scene, layers: &mut [Vec<Color>]) {
+        sample_layers(scene.cam, layers, |r, groups| {
+            self.trace(r, scene, |group, c| groups[group] = groups[group] + c)
+        });
+    }
+}
+
+// clamp_indirect for a single wavelength
+fn clamp(scene: &Scene, contribution: f64, depth: i32) -> f64 {
+    let c = Color::new(contribution, contribution, contribution);
+    clamp_indirect(scene.cam, c, depth).x()
+}
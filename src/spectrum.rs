@@ -0,0 +1,134 @@
+use crate::{color::Color, vec3::Vec3};
+
+// Visible range covered by the RGB to spectrum basis, in nanometers
+pub const LAMBDA_MIN: f64 = 380.;
+pub const LAMBDA_MAX: f64 = 720.;
+
+// Smits' "An RGB to Spectrum Conversion for Reflectances", 10 bins between LAMBDA_MIN and LAMBDA_MAX
+const WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// Value at wavelength lambda of a spectrum whose RGB color is c
+// The basis is fitted for reflectances, so lights and the sky go through it too only as an
+// approximation: it scales linearly past 1 but its white is flat rather than an illuminant's
+pub fn rgb_to_spectrum(c: &Color, lambda: f64) -> f64 {
+    let bin = ((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.).floor();
+    let bin = bin.clamp(0., 9.) as usize;
+
+    let (r, g, b) = (c.x(), c.y(), c.z());
+
+    if r <= g && r <= b {
+        let base = r * WHITE[bin];
+        if g <= b {
+            base + (g - r) * CYAN[bin] + (b - g) * BLUE[bin]
+        } else {
+            base + (b - r) * CYAN[bin] + (g - b) * GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        let base = g * WHITE[bin];
+        if r <= b {
+            base + (r - g) * MAGENTA[bin] + (b - r) * BLUE[bin]
+        } else {
+            base + (b - g) * MAGENTA[bin] + (r - b) * RED[bin]
+        }
+    } else {
+        let base = b * WHITE[bin];
+        if r <= g {
+            base + (r - b) * YELLOW[bin] + (g - r) * GREEN[bin]
+        } else {
+            base + (g - b) * YELLOW[bin] + (r - g) * RED[bin]
+        }
+    }
+}
+
+fn piecewise_gaussian(lambda: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+    let sigma = if lambda < mu { sigma_low } else { sigma_high };
+    let t = (lambda - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+// CIE 1931 colour matching functions, using the multi-lobe fit from
+// Wyman, Sloan & Shirley "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+
+    Vec3::new(x, y, z)
+}
+
+pub fn xyz_to_rgb(xyz: &Vec3) -> Color {
+    let (x, y, z) = (xyz.x(), xyz.y(), xyz.z());
+
+    Color::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    )
+}
+
+// Turns single wavelength samples back into RGB. A constant spectrum of 1 maps to white (1, 1, 1).
+#[derive(Clone)]
+pub struct SpectralToRgb {
+    white: Color,
+    y_integral: f64,
+}
+
+impl SpectralToRgb {
+    pub fn new() -> SpectralToRgb {
+        let steps = 1000;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+
+        let integral = (0..steps)
+            .map(|i| cie_xyz(LAMBDA_MIN + (i as f64 + 0.5) * step) * step)
+            .fold(Vec3::new_zero(), |acc, v| acc + v);
+
+        SpectralToRgb {
+            white: xyz_to_rgb(&(integral / integral.y())),
+            y_integral: integral.y(),
+        }
+    }
+
+    // RGB estimate of a radiance sample taken at lambda, chosen uniformly over the visible range
+    pub fn to_rgb(&self, radiance: f64, lambda: f64) -> Color {
+        let xyz = cie_xyz(lambda) * radiance * (LAMBDA_MAX - LAMBDA_MIN) / self.y_integral;
+        xyz_to_rgb(&xyz) / self.white
+    }
+}
+
+// Planck's law, spectral radiance of a black body at kelvin, up to a constant factor
+fn planck(lambda_nm: f64, kelvin: f64) -> f64 {
+    const C2: f64 = 1.4387769e7; // second radiation constant in nm K
+
+    let lambda = lambda_nm / 1000.;
+    1. / (lambda.powi(5) * ((C2 / (lambda_nm * kelvin)).exp() - 1.))
+}
+
+// Color of a black body glowing at kelvin, scaled to a luminance of 1
+pub fn blackbody(kelvin: f64) -> Color {
+    let steps = 100;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+
+    let xyz = (0..steps)
+        .map(|i| {
+            let lambda = LAMBDA_MIN + (i as f64 + 0.5) * step;
+            cie_xyz(lambda) * planck(lambda, kelvin)
+        })
+        .fold(Vec3::new_zero(), |acc, v| acc + v);
+
+    if xyz.y() <= 0. {
+        return Color::new_zero();
+    }
+
+    let rgb = xyz_to_rgb(&(xyz / xyz.y()));
+    Color::new(rgb.x().max(0.), rgb.y().max(0.), rgb.z().max(0.))
+}
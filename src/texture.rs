@@ -4,6 +4,7 @@ use crate::{
     color::Color,
     perlin::Perlin,
     rt_image::RtImage,
+    spectrum::blackbody,
     vec3::Point3,
 };
 
@@ -12,6 +13,7 @@ pub enum Texture {
     Checker(CheckerTexture),
     Image(ImageTexture),
     Noise(NoiseTexture),
+    Blackbody(BlackbodyTexture),
 }
 
 impl Texture {
@@ -21,6 +23,20 @@ impl Texture {
             Texture::Checker(t) => t.value(u, v, p),
             Texture::Image(t) => t.value(u, v, p),
             Texture::Noise(t) => t.value(u, v, p),
+            Texture::Blackbody(t) => t.value(u, v, p),
+        }
+    }
+
+    // Whether value is black everywhere, such as the emission of a material that doesn't glow
+    pub fn is_black(&self) -> bool {
+        match self {
+            Texture::Solid(t) => t.color_value.length_squared() == 0.,
+            Texture::Checker(t) => t.even.is_black() && t.odd.is_black(),
+            Texture::Image(t) => t.is_black(),
+            Texture::Noise(_) => false,
+            Texture::Blackbody(t) => {
+                t.temperature.is_black() || t.table.iter().all(|c| c.length_squared() == 0.)
+            }
         }
     }
 }
@@ -34,6 +50,11 @@ impl SolidColor {
         Texture::Solid(SolidColor { color_value })
     }
 
+    // A single number for textures used as a parameter, like a Principled material's roughness
+    pub fn gray(value: f64) -> Arc<Texture> {
+        Arc::new(SolidColor::new(Color::new(value, value, value)))
+    }
+
     pub fn _from_rgb(red: f64, green: f64, blue: f64) -> Texture {
         Texture::Solid(SolidColor {
             color_value: Color::new(red, green, blue),
@@ -105,6 +126,13 @@ impl ImageTexture {
 
         self.image.pixel_data(i, j)
     }
+
+    fn is_black(&self) -> bool {
+        self.image.height() > 0
+            && (0..self.image.width()).all(|i| {
+                (0..self.image.height()).all(|j| self.image.pixel_data(i, j).length_squared() == 0.)
+            })
+    }
 }
 
 pub struct NoiseTexture {
@@ -130,3 +158,39 @@ impl NoiseTexture {
     }
 }
 
+// Entries in a BlackbodyTexture's table of colors from cold to max_kelvin
+const BLACKBODY_STEPS: usize = 256;
+
+// Glow of something hot, with the temperature in kelvin given by max_kelvin times the average of
+// temperature's channels. Brightness follows Stefan-Boltzmann, so the hottest parts have
+// luminance intensity and cooler ones are dimmer as well as redder.
+pub struct BlackbodyTexture {
+    temperature: Arc<Texture>,
+    table: Vec<Color>, // the glow at evenly spaced temperatures, since blackbody is slow
+}
+
+impl BlackbodyTexture {
+    pub fn new(temperature: Arc<Texture>, max_kelvin: f64, intensity: f64) -> Texture {
+        let table = (0..BLACKBODY_STEPS)
+            .map(|i| {
+                let t = i as f64 / (BLACKBODY_STEPS - 1) as f64;
+                if t <= 0. {
+                    return Color::new_zero();
+                }
+                blackbody(t * max_kelvin) * intensity * t.powi(4)
+            })
+            .collect();
+
+        Texture::Blackbody(BlackbodyTexture { temperature, table })
+    }
+
+    pub fn value(&self, u: f64, v: f64, p: &Point3) -> Color {
+        let c = self.temperature.value(u, v, p);
+        let t = ((c.x() + c.y() + c.z()) / 3.).clamp(0., 1.);
+
+        let x = t * (BLACKBODY_STEPS - 1) as f64;
+        let i = (x as usize).min(BLACKBODY_STEPS - 2);
+        let f = x - i as f64;
+        (1. - f) * self.table[i] + f * self.table[i + 1]
+    }
+}
@@ -2,7 +2,7 @@ use crate::utils::INF;
 use std::sync::Arc;
 
 use crate::{
-    hittable::{HitRecord, Hittable},
+    hittable::{HitRecord, Hittable, HittableList},
     interval::Interval,
     object::{Aabb, Object},
     ray::Ray,
@@ -37,6 +37,90 @@ impl Hittable for Transform {
             Self::RotY(t) => t.bounding_box(),
         }
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        match self {
+            Self::Translate(t) => t.pdf_value(origin, direction),
+            Self::RotY(t) => t.pdf_value(origin, direction),
+        }
+    }
+
+    fn random(&self, origin: &Point3) -> Vec3 {
+        match self {
+            Self::Translate(t) => t.random(origin),
+            Self::RotY(t) => t.random(origin),
+        }
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        match self {
+            Self::Translate(t) => t.sample_surface(time),
+            Self::RotY(t) => t.sample_surface(time),
+        }
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        match self {
+            Self::Translate(t) => t.surface_pdf(origin, p),
+            Self::RotY(t) => t.surface_pdf(origin, p),
+        }
+    }
+}
+
+impl Transform {
+    // Moving and rotating keeps the area, so the power is the same as the object's
+    pub fn power(&self) -> f64 {
+        match self {
+            Self::Translate(t) => t.object.power(),
+            Self::RotY(t) => t.object.power(),
+        }
+    }
+
+    pub fn light_groups(&self, groups: &mut Vec<String>) {
+        match self {
+            Self::Translate(t) => t.object.light_groups(groups),
+            Self::RotY(t) => t.object.light_groups(groups),
+        }
+    }
+
+    // hit, counting the BVH nodes visited under the transform
+    pub fn hit_counting(
+        &self,
+        r: &Ray,
+        ray_t: &Interval,
+        visits: &mut u32,
+    ) -> Option<HitRecord<'_>> {
+        match self {
+            Self::Translate(t) => {
+                let rec = t.object.hit_counting(&t.to_object(r), ray_t, visits)?;
+                Some(t.to_world(rec))
+            }
+            Self::RotY(t) => {
+                let rec = t.object.hit_counting(&t.ray_to_object(r), ray_t, visits)?;
+                Some(t.rec_to_world(rec))
+            }
+        }
+    }
+
+    // Adds the emitters under this transform to lights, wrapped in the same transform
+    pub fn collect_lights(&self, lights: &mut HittableList) {
+        let object = match self {
+            Self::Translate(t) => &t.object,
+            Self::RotY(t) => &t.object,
+        };
+
+        let mut inner = HittableList::new();
+        object.collect_lights(&mut inner);
+        if inner.objects.is_empty() {
+            return;
+        }
+
+        let inner = Arc::new(Object::List(Arc::new(inner)));
+        lights.add(match self {
+            Self::Translate(t) => Translate::new(inner, t.offset),
+            Self::RotY(t) => t.with_object(inner),
+        });
+    }
 }
 
 impl Translate {
@@ -53,24 +137,47 @@ impl Translate {
     }
 }
 
+impl Translate {
+    // Move ray backwards by the offset
+    fn to_object(&self, r: &Ray) -> Ray {
+        Ray::new_timed(r.origin() - self.offset, r.direction(), r.time())
+    }
+
+    fn to_world<'a>(&self, mut rec: HitRecord<'a>) -> HitRecord<'a> {
+        rec.p = rec.p + self.offset;
+        rec
+    }
+}
+
 impl Hittable for Translate {
     fn hit(&self, r: &crate::ray::Ray, ray_t: &Interval) -> Option<HitRecord> {
-        // Move ray backwards by the offset
-        let offset_r = Ray::new_timed(r.origin() - self.offset, r.direction(), r.time());
-
         // Determine where (if any) an intersection occurs along the offset ray
-        match self.object.hit(&offset_r, ray_t) {
-            None => None,
-            Some(mut rec) => {
-                rec.p = rec.p + self.offset;
-                Some(rec)
-            }
-        }
+        let rec = self.object.hit(&self.to_object(r), ray_t)?;
+        Some(self.to_world(rec))
     }
 
     fn bounding_box(&self) -> Option<&crate::object::Aabb> {
         Some(&self.bbox)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.object.pdf_value(&(*origin - self.offset), direction)
+    }
+
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.object.random(&(*origin - self.offset))
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.object.sample_surface(time)?;
+        rec.p = rec.p + self.offset;
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        self.object
+            .surface_pdf(&(*origin - self.offset), &(*p - self.offset))
+    }
 }
 
 #[derive(Clone)]
@@ -83,63 +190,53 @@ pub struct RotateY {
 
 impl Hittable for RotateY {
     fn hit(&self, r: &Ray, ray_t: &Interval) -> Option<HitRecord> {
-        let mut origin = r.origin();
-        let mut direction = r.direction();
-
-        origin.set(
-            0,
-            self.cos_theta * r.origin().x() - self.sin_theta * r.origin().z(),
-        );
-        origin.set(
-            2,
-            self.sin_theta * r.origin().x() + self.cos_theta * r.origin().z(),
-        );
-
-        direction.set(
-            0,
-            self.cos_theta * r.direction().x() - self.sin_theta * r.direction().z(),
-        );
-        direction.set(
-            2,
-            self.sin_theta * r.direction().x() + self.cos_theta * r.direction().z(),
-        );
-
-        let rotated_r = Ray::new_timed(origin, direction, r.time());
-
         // Determine where (if any) an intersection occurs in object space
-        match self.object.hit(&rotated_r, ray_t) {
-            None => None,
-            Some(mut rec) => {
-                // Chane the intersection point from object space to world space
-                let mut p = rec.p;
-                p.set(0, self.cos_theta * rec.p.x() + self.sin_theta * rec.p.z());
-                p.set(2, -self.sin_theta * rec.p.x() + self.cos_theta * rec.p.z());
-
-                // Change the normal from object space to world space
-                let mut normal = rec.normal;
-                normal.set(
-                    0,
-                    self.cos_theta * rec.normal.x() + self.sin_theta * rec.normal.z(),
-                );
-                normal.set(
-                    2,
-                    -self.sin_theta * rec.normal.x() + self.cos_theta * rec.normal.z(),
-                );
-
-                rec.p = p;
-                rec.normal = normal;
-
-                Some(rec)
-            }
-        }
+        let rec = self.object.hit(&self.ray_to_object(r), ray_t)?;
+        Some(self.rec_to_world(rec))
     }
 
     fn bounding_box(&self) -> Option<&Aabb> {
         Some(&self.bbox)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.object
+            .pdf_value(&self.to_object(origin), &self.to_object(direction))
+    }
+
+    fn random(&self, origin: &Point3) -> Vec3 {
+        self.to_world(&self.object.random(&self.to_object(origin)))
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.object.sample_surface(time)?;
+        rec.p = self.to_world(&rec.p);
+        rec.normal = self.to_world(&rec.normal);
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, origin: &Point3, p: &Point3) -> f64 {
+        self.object
+            .surface_pdf(&self.to_object(origin), &self.to_object(p))
+    }
 }
 
 impl RotateY {
+    fn ray_to_object(&self, r: &Ray) -> Ray {
+        Ray::new_timed(
+            self.to_object(&r.origin()),
+            self.to_object(&r.direction()),
+            r.time(),
+        )
+    }
+
+    // Change the intersection point and normal from object space to world space
+    fn rec_to_world<'a>(&self, mut rec: HitRecord<'a>) -> HitRecord<'a> {
+        rec.p = self.to_world(&rec.p);
+        rec.normal = self.to_world(&rec.normal);
+        rec
+    }
+
     pub fn new(p: Arc<Object>, angle: f64) -> Object {
         let radians = angle.to_radians();
         let sin_theta = radians.sin();
@@ -184,4 +281,25 @@ impl RotateY {
             sin_theta,
         }))
     }
+
+    // The same rotation applied to another object
+    fn with_object(&self, p: Arc<Object>) -> Object {
+        RotateY::new(p, self.sin_theta.atan2(self.cos_theta).to_degrees())
+    }
+
+    fn to_object(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x() - self.sin_theta * v.z(),
+            v.y(),
+            self.sin_theta * v.x() + self.cos_theta * v.z(),
+        )
+    }
+
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.cos_theta * v.x() + self.sin_theta * v.z(),
+            v.y(),
+            -self.sin_theta * v.x() + self.cos_theta * v.z(),
+        )
+    }
 }
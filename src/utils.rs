@@ -1,15 +1,49 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use rand::Rng;
 
 pub const INF: f64 = f64::INFINITY;
 
+// A source of uniform numbers in [0, 1) that replaces the thread rng, e.g. a Metropolis chain's primary samples
+pub trait SampleStream {
+    fn next_sample(&mut self) -> f64;
+}
+
+thread_local! {
+    // Set through sampler::set_sample_stream
+    pub static SAMPLE_STREAM: RefCell<Option<Rc<RefCell<dyn SampleStream>>>> = RefCell::new(None);
+}
+
+// Threads with a stream set, so renders without one never look at the thread local
+pub static ACTIVE_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+fn next_sample() -> Option<f64> {
+    if ACTIVE_STREAMS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+
+    SAMPLE_STREAM.with(|s| s.borrow().as_ref().map(|stream| stream.borrow_mut().next_sample()))
+}
+
 pub fn random_double() -> f64 {
-    rand::random::<f64>()
+    next_sample().unwrap_or_else(rand::random::<f64>)
 }
 
 pub fn random_range(min: f64, max: f64) -> f64 {
-    rand::thread_rng().gen_range(min..max)
+    match next_sample() {
+        Some(u) => min + (max - min) * u,
+        None => rand::thread_rng().gen_range(min..max),
+    }
 }
 
 pub fn random_int(min: i64, max: i64) -> i64 {
-    rand::thread_rng().gen_range(min..=max)
+    match next_sample() {
+        Some(u) => {
+            let n = (u * (max - min + 1) as f64) as i64;
+            min + n.min(max - min)
+        }
+        None => rand::thread_rng().gen_range(min..=max),
+    }
 }